@@ -0,0 +1,382 @@
+use pulldown_cmark::Options as ParserOptions;
+
+use super::{fmt::Write, labels_match, Cow, EscapePolicy, LinkCategory, Options, State};
+
+/// Escape the characters of `t` which would otherwise be parsed as markup within a line, given that `preceding`
+/// is the character written right before it on the same line, if any.
+pub(crate) fn escape_special_characters<'a>(
+    t: &'a str,
+    preceding: Option<char>,
+    state: &State<'a>,
+    options: &Options<'a>,
+) -> Cow<'a, str> {
+    // The content of metadata blocks and autolinks is taken literally.
+    if state.is_in_code_block()
+        || state.in_metadata_block
+        || t.is_empty()
+        || state.link_stack.last() == Some(&LinkCategory::AngleBracketed)
+    {
+        return Cow::Borrowed(t);
+    }
+
+    let escape = !state.unescaped_in_source;
+    let conservative = options.escape_policy != EscapePolicy::Minimal;
+    let paranoid = escape && options.escape_policy == EscapePolicy::Paranoid;
+    let first = t.chars().next().expect("at least one char");
+    let first_special = escape && conservative && options.special_characters().contains(first);
+    let closes_heading = state.current_heading.is_some()
+        && t.ends_with('#')
+        && (conservative
+            || (state.setext_heading.is_none()
+                && t.trim_end_matches('#')
+                    .chars()
+                    .last()
+                    .or(preceding)
+                    .map_or(true, |c| c == ' ' || c == '\t')));
+    let ends_with_special = escape && ((state.next_is_link_like && t.ends_with("!")) || closes_heading);
+    let table_contains_pipe = escape && !state.table_alignments.is_empty() && t.contains("|");
+    let inline_delimiters = if escape {
+        inline_delimiter_offsets(t, preceding, state, options)
+    } else {
+        Vec::new()
+    };
+    let has_invisible_characters = options.numeric_character_references && t.chars().any(is_invisible);
+    let has_smart_punctuation = options.reverse_smart_punctuation
+        && !state.text_in_source
+        && t.chars().any(|c| smart_punctuation_source(c).is_some());
+    if first_special
+        || ends_with_special
+        || table_contains_pipe
+        || !inline_delimiters.is_empty()
+        || has_invisible_characters
+        || has_smart_punctuation
+        || (paranoid && t.contains(|c: char| c.is_ascii_punctuation()))
+    {
+        let mut s = String::with_capacity(t.len() + 1);
+        let mut inline_delimiters = inline_delimiters.into_iter().peekable();
+        for (i, c) in t.char_indices() {
+            if has_invisible_characters && is_invisible(c) {
+                write!(s, "&#{};", u32::from(c)).expect("writing to a string never fails");
+                continue;
+            }
+            if let Some(source) = smart_punctuation_source(c).filter(|_| has_smart_punctuation) {
+                s.push_str(source);
+                continue;
+            }
+            let is_inline_delimiter = inline_delimiters.next_if_eq(&i).is_some();
+            if (i == 0 && first_special)
+                || (i == t.len() - 1 && ends_with_special)
+                || (c == '|' && table_contains_pipe)
+                || is_inline_delimiter
+                || (paranoid && c.is_ascii_punctuation())
+            {
+                s.push('\\');
+            }
+            s.push(c);
+        }
+        Cow::Owned(s)
+    } else {
+        Cow::Borrowed(t)
+    }
+}
+
+/// The ASCII characters which smart punctuation turns into `c`, if it's one of its curly quotes,
+/// dashes or ellipsis.
+pub(crate) fn smart_punctuation_source(c: char) -> Option<&'static str> {
+    Some(match c {
+        '\u{201c}' | '\u{201d}' => "\"",
+        '\u{2018}' | '\u{2019}' => "'",
+        '\u{2013}' => "--",
+        '\u{2014}' => "---",
+        '\u{2026}' => "...",
+        _ => return None,
+    })
+}
+
+/// Returns `true` if `c` can't be seen, or is easily confused with a space, like control characters,
+/// zero-width and no-break spaces or bidirectional formatting characters.
+fn is_invisible(c: char) -> bool {
+    (c.is_control() && c != '\n' && c != '\t')
+        || matches!(
+            c,
+            '\u{a0}' | '\u{ad}' | '\u{2000}'..='\u{200f}' | '\u{2028}'..='\u{202f}' | '\u{205f}'..='\u{206f}' | '\u{feff}'
+        )
+}
+
+/// Returns `true` if `t` starts with a named, decimal or hexadecimal entity reference like `&copy;`, `&#35;`
+/// or `&#x23;`.
+fn starts_with_entity_reference(t: &str) -> bool {
+    let Some((reference, _)) = t.strip_prefix('&').and_then(|t| t.split_once(';')) else {
+        return false;
+    };
+    let is_numeric = |digits: &str, max_len: usize, is_digit: fn(&u8) -> bool| {
+        (1..=max_len).contains(&digits.len()) && digits.as_bytes().iter().all(is_digit)
+    };
+    match reference.strip_prefix('#') {
+        Some(hex) if hex.starts_with(['x', 'X']) => is_numeric(&hex[1..], 6, u8::is_ascii_hexdigit),
+        Some(decimal) => is_numeric(decimal, 7, u8::is_ascii_digit),
+        None => {
+            (2..=32).contains(&reference.len())
+                && reference.as_bytes()[0].is_ascii_alphabetic()
+                && reference.bytes().all(|b| b.is_ascii_alphanumeric())
+        }
+    }
+}
+
+/// The ascending byte offsets of the characters in `t` which could open or close an inline construct
+/// like emphasis, a code span, a raw HTML tag, an autolink, a link or an entity reference, or one of the
+/// extensions enabled in [`Options::parser_options`].
+fn inline_delimiter_offsets(t: &str, preceding: Option<char>, state: &State<'_>, options: &Options<'_>) -> Vec<usize> {
+    let chars: Vec<(usize, char)> = t.char_indices().collect();
+    let extensions = options.parser_options;
+    // Superscript and subscript delimiters are written by us in that case, so the parser must support them.
+    let super_sub_script_markers = !options.use_html_for_super_sub_script;
+    let is_delimiter = |c: char| match c {
+        '~' => {
+            super_sub_script_markers
+                || extensions.intersects(ParserOptions::ENABLE_STRIKETHROUGH | ParserOptions::ENABLE_SUBSCRIPT)
+        }
+        '^' => super_sub_script_markers || extensions.contains(ParserOptions::ENABLE_SUPERSCRIPT),
+        _ => true,
+    };
+    // Inline content that follows starts with punctuation, like `` ` `` or `*`.
+    let following = if state.next_is_inline { '!' } else { ' ' };
+    let char_before = |index: usize| {
+        index
+            .checked_sub(1)
+            .map_or(preceding.unwrap_or(' '), |index| chars[index].1)
+    };
+    let char_after = |index: usize| chars.get(index).map_or(following, |(_, c)| *c);
+
+    // Quotes and runs of dashes or dots would become typographic, also along with those written back in ASCII.
+    let smart_punctuation = extensions.contains(ParserOptions::ENABLE_SMART_PUNCTUATION);
+    let in_link_text = !state.link_stack.is_empty() || !state.image_stack.is_empty();
+
+    let mut offsets = Vec::new();
+    let mut index = 0;
+    while let Some(&(offset, c)) = chars.get(index) {
+        match c {
+            '`' => offsets.push(offset),
+            // A backslash at the end may be followed by punctuation or a line ending.
+            '\\' if chars
+                .get(index + 1)
+                .map_or(true, |(_, c)| *c == '\n' || c.is_ascii_punctuation()) =>
+            {
+                offsets.push(offset)
+            }
+            '&' if starts_with_entity_reference(&t[offset..]) => offsets.push(offset),
+            '<' if matches!(char_after(index + 1), 'a'..='z' | 'A'..='Z' | '/' | '!' | '?') => offsets.push(offset),
+            // Brackets within the text of links and images could end it early if they aren't balanced.
+            '[' | ']' if in_link_text => offsets.push(offset),
+            '[' if opens_link(&t[offset..], state) || opens_extension(&t[offset..], extensions) => offsets.push(offset),
+            '$' if extensions.contains(ParserOptions::ENABLE_MATH) => offsets.push(offset),
+            '|' if extensions.contains(ParserOptions::ENABLE_TABLES) => offsets.push(offset),
+            '"' | '\'' if smart_punctuation => offsets.push(offset),
+            '-' | '.'
+                if smart_punctuation
+                    && [char_before(index), char_after(index + 1)].iter().any(|&other| {
+                        other == c || smart_punctuation_source(other).is_some_and(|s| s.starts_with(c))
+                    }) =>
+            {
+                offsets.push(offset)
+            }
+            '}' if extensions.contains(ParserOptions::ENABLE_HEADING_ATTRIBUTES) && state.current_heading.is_some() => {
+                offsets.push(offset)
+            }
+            '*' | '_' | '~' | '^' if is_delimiter(c) => {
+                let run = chars[index..].iter().take_while(|(_, d)| *d == c).count();
+                let (before, after) = (char_before(index), char_after(index + run));
+                let left_flanking = !after.is_whitespace()
+                    && (!is_punctuation(after) || before.is_whitespace() || is_punctuation(before));
+                let right_flanking = !before.is_whitespace()
+                    && (!is_punctuation(before) || after.is_whitespace() || is_punctuation(after));
+                // Like `*`, and unlike `_`, `~~` for strikethrough may delimit within words.
+                let (can_open, can_close) = if c == '*' || (c == '~' && run == 2) {
+                    (left_flanking, right_flanking)
+                } else {
+                    (
+                        left_flanking && (!right_flanking || is_punctuation(before)),
+                        right_flanking && (!left_flanking || is_punctuation(after)),
+                    )
+                };
+                // Closing delimiters can only close what isn't escaped, which is emphasis written by us.
+                let closes_own_emphasis = can_close
+                    && match c {
+                        '~' => super_sub_script_markers || extensions.contains(ParserOptions::ENABLE_STRIKETHROUGH),
+                        '^' => super_sub_script_markers,
+                        _ => options.emphasis_token == c || options.strong_token.starts_with(c),
+                    };
+                if can_open || closes_own_emphasis {
+                    offsets.extend(chars[index..index + run].iter().map(|(offset, _)| *offset));
+                }
+                index += run;
+                continue;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    offsets
+}
+
+/// Returns `true` if the `[` at the start of `t` could start a link, as its closing bracket is followed by
+/// a destination, or it encloses a label seen in a reference link or image so far.
+fn opens_link(t: &str, state: &State<'_>) -> bool {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (offset, c) in t.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    let label = &t[1..offset];
+                    return t[offset + 1..].starts_with('(') || is_known_label(label, state);
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Returns `true` if the `[` at the start of `t` could start a footnote reference, a wikilink or the marker of
+/// a GFM alert like `[!NOTE]`, depending on the `extensions` in use.
+fn opens_extension(t: &str, extensions: ParserOptions) -> bool {
+    const ALERT_KINDS: [&str; 5] = ["note", "tip", "important", "warning", "caution"];
+    let rest = &t[1..];
+    (extensions.contains(ParserOptions::ENABLE_FOOTNOTES) && rest.starts_with('^'))
+        || (extensions.contains(ParserOptions::ENABLE_WIKILINKS) && rest.starts_with('['))
+        || (extensions.contains(ParserOptions::ENABLE_GFM)
+            && rest
+                .strip_prefix('!')
+                .and_then(|rest| rest.split_once(']'))
+                .is_some_and(|(kind, _)| ALERT_KINDS.iter().any(|known| known.eq_ignore_ascii_case(kind))))
+}
+
+/// Returns `true` if `label` matches the label of a reference definition seen so far.
+fn is_known_label(label: &str, state: &State<'_>) -> bool {
+    !label.trim().is_empty()
+        && state
+            .shortcuts
+            .iter()
+            .chain(&state.written_shortcuts)
+            .any(|(known, _, _)| labels_match(known, label))
+}
+
+/// Returns `true` if `c` is ASCII or Unicode punctuation, or a symbol, in the sense of CommonMark.
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || (!c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace() && !c.is_control())
+}
+
+/// Escape what would start a block at the beginning of each line of `t`, which is written at the start of a line
+/// if `at_line_start` is set, on the first line of its block if `first_line` is also set.
+/// If `continues` is set, the last line is continued by inline content that doesn't start with whitespace.
+///
+/// Lines after the first of a block only start a block if it can interrupt a paragraph, so `2. ` is fine there
+/// while `1. a` is not.
+pub(crate) fn escape_block_markers<'a>(
+    t: Cow<'a, str>,
+    at_line_start: bool,
+    first_line: bool,
+    continues: bool,
+    options: &Options<'_>,
+) -> Cow<'a, str> {
+    let mut escapes = Vec::new();
+    let mut line_start = 0;
+    let line_count = t.split('\n').count();
+    for (index, line) in t.split('\n').enumerate() {
+        if index > 0 || at_line_start {
+            let continues = continues && index + 1 == line_count;
+            if let Some(offset) =
+                block_marker_escape_offset(line, index == 0 && first_line, continues, options.parser_options)
+            {
+                escapes.push(line_start + offset);
+            }
+        }
+        line_start += line.len() + 1;
+    }
+    if escapes.is_empty() {
+        return t;
+    }
+
+    let mut s = String::with_capacity(t.len() + escapes.len());
+    let mut last = 0;
+    for escape in escapes {
+        s.push_str(&t[last..escape]);
+        s.push('\\');
+        last = escape;
+    }
+    s.push_str(&t[last..]);
+    Cow::Owned(s)
+}
+
+/// The byte offset of the character in `line` to escape so it doesn't start a block, if it would, also with
+/// the given parser `extensions`.
+/// If `continues` is set, more inline content that doesn't start with whitespace follows `line`.
+fn block_marker_escape_offset(
+    line: &str,
+    first_line: bool,
+    continues: bool,
+    extensions: ParserOptions,
+) -> Option<usize> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let rest = &line.as_bytes()[indent..];
+    let ends_marker = |index: usize| rest.get(index).map_or(!continues, |b| matches!(b, b' ' | b'\t'));
+    let has_content = |index: usize| continues || rest.iter().skip(index).any(|b| !b.is_ascii_whitespace());
+    let only = |marker: u8| {
+        !continues
+            && rest
+                .iter()
+                .filter(|b| **b != b' ' && **b != b'\t')
+                .all(|b| *b == marker)
+    };
+
+    let offset = match *rest.first()? {
+        b'>' => Some(0),
+        b'#' => {
+            let level = rest.iter().take_while(|b| **b == b'#').count();
+            (level <= 6 && ends_marker(level)).then_some(0)
+        }
+        b'<' => {
+            matches!(rest.get(1), Some(b) if b.is_ascii_alphabetic() || matches!(b, b'/' | b'!' | b'?')).then_some(0)
+        }
+        marker @ (b'`' | b'~') => rest.starts_with(&[marker; 3]).then_some(0),
+        marker @ (b'-' | b'*' | b'_' | b'+' | b'=') => {
+            let is_thematic_break =
+                marker != b'+' && marker != b'=' && only(marker) && rest.iter().filter(|b| **b == marker).count() >= 3;
+            let is_setext_underline = !first_line
+                && !continues
+                && matches!(marker, b'=' | b'-')
+                && rest.iter().rev().skip_while(|b| **b == b' ').all(|b| *b == marker);
+            let is_bullet = marker != b'_' && marker != b'=' && ends_marker(1) && (first_line || has_content(1));
+            let is_metadata_fence = marker == b'+'
+                && extensions.contains(ParserOptions::ENABLE_PLUSES_DELIMITED_METADATA_BLOCKS)
+                && only(marker)
+                && rest.starts_with(b"+++");
+            (is_thematic_break || is_setext_underline || is_bullet || is_metadata_fence).then_some(0)
+        }
+        b':' => (extensions.contains(ParserOptions::ENABLE_DEFINITION_LIST) && ends_marker(1)).then_some(0),
+        // Reference definitions and task list markers start a block, and whether the text forms one can depend
+        // on what follows it.
+        b'[' => first_line.then_some(0),
+        b'0'..=b'9' => {
+            let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+            let is_ordered = digits <= 9
+                && matches!(rest.get(digits), Some(b'.' | b')'))
+                && ends_marker(digits + 1)
+                && (first_line
+                    || (rest[..digits - 1].iter().all(|b| *b == b'0')
+                        && rest[digits - 1] == b'1'
+                        && has_content(digits + 1)));
+            is_ordered.then_some(digits)
+        }
+        _ => None,
+    };
+    offset.map(|offset| indent + offset)
+}
@@ -0,0 +1,180 @@
+use std::io;
+
+use super::{
+    cmark_resume_with_options, cmark_resume_with_source_range_and_options, fmt, Borrow, Error, Event, Options, Range,
+    State,
+};
+
+/// Adapts an [`io::Write`] sink to [`fmt::Write`], keeping the [`io::Error`] that
+/// [`fmt::Error`] can't carry.
+///
+/// Bytes are passed to the sink as they are produced, so callers who want buffering
+/// should pass something like a [`std::io::BufWriter`].
+pub(crate) struct IoWriter<W> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+impl<W> IoWriter<W>
+where
+    W: io::Write,
+{
+    pub(crate) fn new(inner: W) -> Self {
+        IoWriter { inner, error: None }
+    }
+
+    /// Turn a [`fmt::Error`] caused by the sink into [`Error::Io`].
+    pub(crate) fn convert<T>(self, res: Result<T, Error>) -> Result<T, Error> {
+        match (res, self.error) {
+            (Err(Error::FormatFailed(_)), Some(err)) => Err(Error::Io(err)),
+            (res, _) => res,
+        }
+    }
+}
+
+impl<W> fmt::Write for IoWriter<W>
+where
+    W: io::Write,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+/// As [`cmark_io_with_options()`], but with default [`Options`].
+pub fn cmark_io<'a, I, E, W>(events: I, writer: W) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = E>,
+    E: Borrow<Event<'a>>,
+    W: io::Write,
+{
+    cmark_io_with_options(events, writer, Default::default())
+}
+
+/// As [`cmark_resume_io_with_options()`], but with default [`Options`].
+pub fn cmark_resume_io<'a, I, E, W>(events: I, writer: W, state: Option<State<'a>>) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = E>,
+    E: Borrow<Event<'a>>,
+    W: io::Write,
+{
+    cmark_resume_io_with_options(events, writer, state, Options::default())
+}
+
+/// As [`cmark_resume_io_with_options()`], but with the [`State`] finalized.
+pub fn cmark_io_with_options<'a, I, E, W>(events: I, writer: W, options: Options<'_>) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = E>,
+    E: Borrow<Event<'a>>,
+    W: io::Write,
+{
+    let mut writer = IoWriter::new(writer);
+    let res = cmark_resume_with_options(events, &mut writer, Default::default(), options)
+        .and_then(|state| state.finalize(&mut writer));
+    writer.convert(res)
+}
+
+/// As [`cmark_resume_with_options()`](super::cmark_resume_with_options), but writes the
+/// serialized bytes straight into an [`io::Write`] `writer`.
+///
+/// *Errors* with [`Error::Io`] if `writer` fails, otherwise as
+/// [`cmark_resume_with_options()`](super::cmark_resume_with_options).
+pub fn cmark_resume_io_with_options<'a, I, E, W>(
+    events: I,
+    writer: W,
+    state: Option<State<'a>>,
+    options: Options<'_>,
+) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = E>,
+    E: Borrow<Event<'a>>,
+    W: io::Write,
+{
+    let mut writer = IoWriter::new(writer);
+    let res = cmark_resume_with_options(events, &mut writer, state, options);
+    writer.convert(res)
+}
+
+/// As [`cmark_io_with_source_range_and_options()`], but with default [`Options`].
+pub fn cmark_io_with_source_range<'a, I, E, W>(
+    event_and_ranges: I,
+    source: &'a str,
+    writer: W,
+) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = (E, Option<Range<usize>>)>,
+    E: Borrow<Event<'a>>,
+    W: io::Write,
+{
+    cmark_io_with_source_range_and_options(event_and_ranges, source, writer, Default::default())
+}
+
+/// As [`cmark_resume_io_with_source_range_and_options()`], but with default [`Options`].
+pub fn cmark_resume_io_with_source_range<'a, I, E, W>(
+    event_and_ranges: I,
+    source: &'a str,
+    writer: W,
+    state: Option<State<'a>>,
+) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = (E, Option<Range<usize>>)>,
+    E: Borrow<Event<'a>>,
+    W: io::Write,
+{
+    cmark_resume_io_with_source_range_and_options(event_and_ranges, source, writer, state, Options::default())
+}
+
+/// As [`cmark_resume_io_with_source_range_and_options()`], but with the [`State`] finalized.
+pub fn cmark_io_with_source_range_and_options<'a, I, E, W>(
+    event_and_ranges: I,
+    source: &'a str,
+    writer: W,
+    options: Options<'_>,
+) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = (E, Option<Range<usize>>)>,
+    E: Borrow<Event<'a>>,
+    W: io::Write,
+{
+    let mut writer = IoWriter::new(writer);
+    let res = cmark_resume_with_source_range_and_options(event_and_ranges, source, &mut writer, None, options)
+        .and_then(|state| state.finalize(&mut writer));
+    writer.convert(res)
+}
+
+/// As [`cmark_resume_with_source_range_and_options()`](super::cmark_resume_with_source_range_and_options),
+/// but writes the serialized bytes straight into an [`io::Write`] `writer`.
+///
+/// *Errors* with [`Error::Io`] if `writer` fails, otherwise as
+/// [`cmark_resume_with_source_range_and_options()`](super::cmark_resume_with_source_range_and_options).
+pub fn cmark_resume_io_with_source_range_and_options<'a, I, E, W>(
+    event_and_ranges: I,
+    source: &'a str,
+    writer: W,
+    state: Option<State<'a>>,
+    options: Options<'_>,
+) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = (E, Option<Range<usize>>)>,
+    E: Borrow<Event<'a>>,
+    W: io::Write,
+{
+    let mut writer = IoWriter::new(writer);
+    let res = cmark_resume_with_source_range_and_options(event_and_ranges, source, &mut writer, state, options);
+    writer.convert(res)
+}
+
+impl State<'_> {
+    /// As [`State::finalize()`], but writes the remaining shortcuts into an [`io::Write`] `writer`.
+    pub fn finalize_io<W>(self, writer: W) -> Result<Self, Error>
+    where
+        W: io::Write,
+    {
+        let mut writer = IoWriter::new(writer);
+        let res = self.finalize(&mut writer);
+        writer.convert(res)
+    }
+}
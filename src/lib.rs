@@ -14,27 +14,51 @@
 //! cmark(events, &mut output_markdown).unwrap();
 //! assert_eq!(output_markdown, input_markdown);
 //! ```
+//!
+//! # Features
+//!
+//! * **unicode-width**
+//!   * Measure text by grapheme cluster and East Asian display width when padding table columns
+//!     or wrapping lines, instead of counting `char`s.
 
 #![deny(rust_2018_idioms)]
 #![deny(missing_docs)]
 
 use std::{
     borrow::{Borrow, Cow},
-    collections::HashSet,
-    fmt,
+    collections::{BTreeSet, VecDeque},
+    fmt::{self, Write},
     ops::Range,
 };
 
-use pulldown_cmark::{Alignment as TableAlignment, BlockQuoteKind, Event, LinkType, MetadataBlockKind, Tag, TagEnd};
+use pulldown_cmark::{
+    Alignment as TableAlignment, BlockQuoteKind, CowStr, Event, HeadingLevel, LinkType, MetadataBlockKind, Tag, TagEnd,
+};
 
+mod escape;
+mod io;
+mod optimize;
+mod serializer;
 mod source_range;
 mod text_modifications;
+mod validate;
 
+use escape::*;
+pub use io::{
+    cmark_io, cmark_io_with_options, cmark_io_with_source_range, cmark_io_with_source_range_and_options,
+    cmark_resume_io, cmark_resume_io_with_options, cmark_resume_io_with_source_range,
+    cmark_resume_io_with_source_range_and_options,
+};
+pub use optimize::cmark_with_optimized_escapes;
+pub use serializer::Serializer;
+use source_range::cmark_resume_one_event_with_source_range;
 pub use source_range::{
     cmark_resume_with_source_range, cmark_resume_with_source_range_and_options, cmark_with_source_range,
     cmark_with_source_range_and_options,
 };
 use text_modifications::*;
+pub use validate::validate_events;
+use validate::Validator;
 
 /// Similar to [Pulldown-Cmark-Alignment][Alignment], but with required
 /// traits for comparison to allow testing.
@@ -50,6 +74,20 @@ pub enum Alignment {
     Right,
 }
 
+impl Alignment {
+    /// The minimum width of a column so that the delimiter row can represent its alignment.
+    fn min_delimiter_width(self) -> usize {
+        match self {
+            // Must at least represent `-`.
+            Alignment::None => 1,
+            // Must at least represent `:-` or `-:`
+            Alignment::Left | Alignment::Right => 2,
+            // Must at least represent `:-:`
+            Alignment::Center => 3,
+        }
+    }
+}
+
 impl<'a> From<&'a TableAlignment> for Alignment {
     fn from(s: &'a TableAlignment) -> Self {
         match *s {
@@ -70,6 +108,175 @@ pub enum CodeBlockKind {
     Fenced,
 }
 
+/// How headings are written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HeadingStyle {
+    /// Prefix headings with `#` characters, like `## Heading`
+    Atx,
+    /// Prefix and close headings with `#` characters, like `## Heading ##`
+    ClosedAtx,
+    /// Underline level 1 and 2 headings with `=` or `-` characters, and use [`HeadingStyle::Atx`]
+    /// for all other levels, or if the content can't be underlined
+    Setext,
+}
+
+/// How hard line breaks are written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HardBreakStyle {
+    /// Two spaces at the end of the line
+    TrailingSpaces,
+    /// A backslash at the end of the line, which survives the trimming of trailing whitespace
+    Backslash,
+    /// An HTML `<br>` tag at the end of the line
+    Html,
+}
+
+/// How eagerly characters with a meaning in Markdown are backslash-escaped in text.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EscapePolicy {
+    /// Only escape what would otherwise change how the output is parsed, for documents edited by humans
+    Minimal,
+    /// Like [`EscapePolicy::Minimal`], but also escape special characters at the start of each text,
+    /// and closing sequences of headings
+    Conservative,
+    /// Escape every ASCII punctuation character, for text which may come from untrusted sources
+    Paranoid,
+}
+
+/// How thoroughly the nesting of events is checked before they are written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EventValidation {
+    /// Only reject the events that can't be written at all, and write all others as well as possible
+    Unchecked,
+    /// Reject each event that can't be produced by parsing Markdown where it appears, like an `End` that doesn't
+    /// close the innermost open container, text outside of a block holding inline content, or a table cell
+    /// outside of a table row, as [`validate_events()`] does.
+    ///
+    /// Events passed at once are all checked before any of them is written, and containers left open
+    /// are reported when the serialization [finishes](Serializer::finish()).
+    Strict,
+    /// Repair the events where possible instead of rejecting them, and record each [`Repair`] in
+    /// [`State::repairs`]:
+    ///
+    /// * containers left open are closed when the serialization [finishes](Serializer::finish()),
+    ///   or before an `End` event of a container they are within
+    /// * events which can't appear within the innermost open container are placed within the closest one
+    ///   that can hold them, closing the containers within it, like a heading within a heading
+    /// * events are wrapped in the containers they need, like inline events outside of blocks in a paragraph,
+    ///   an item outside of a list in a list, or a table cell directly within a table in a row
+    /// * containers which can't appear anywhere within the open containers, like links within links or
+    ///   table cells outside of tables, are replaced by their content
+    /// * `End` events which don't close any open container are dropped
+    Repair,
+}
+
+/// The syntax links and images are written in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LinkStyle {
+    /// Keep the syntax of each link and image, like `[text](uri)`, `[text][label]` or `[text]`
+    Preserve,
+    /// Write all links and images inline, like `[text](uri "title")`, and drop their reference definitions
+    Inline,
+    /// Write all links and images as full reference links, like `[text][label]`.
+    ///
+    /// Inline links and images use the label of an existing definition with the same URI and title,
+    /// or otherwise the lowest number which isn't the label of a definition seen so far.
+    /// Reference links and images are relabeled the same way if their label was generated for another
+    /// URI or title before their definition was seen.
+    Reference,
+}
+
+/// Where the definitions of reference links and images are written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ReferenceDefinitionPlacement {
+    /// Write all definitions at the end of the document, with [`State::finalize()`]
+    EndOfDocument,
+    /// Write definitions before the next top-level heading of the given level or a higher one,
+    /// and the remaining ones at the end of the document
+    EndOfSection(HeadingLevel),
+    /// Write definitions right after the top-level block they were first used in
+    AfterBlock,
+}
+
+/// The order in which the definitions of reference links and images are written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ReferenceDefinitionOrder {
+    /// In the order their links and images appear in
+    FirstUse,
+    /// Sorted by label, ignoring case
+    Alphabetical,
+    /// The definitions of links in the order they appear in, followed by the ones of images
+    LinksBeforeImages,
+}
+
+/// How the items of lists are spaced, which decides whether they are tight or loose when parsed again.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ListSpacing {
+    /// Keep the spacing of the events, which makes lists loose if their items contain paragraphs
+    Preserve,
+    /// Write lists tight, without blank lines between their items or the blocks within them.
+    ///
+    /// A blank line is kept where the blocks of an item would be merged otherwise, like two paragraphs,
+    /// which leaves the list loose and is counted in [`State::blank_lines_kept_in_tight_lists`].
+    Tight,
+    /// Write lists loose, with exactly one blank line between items at every nesting level
+    Loose,
+}
+
+/// How the content of list items is indented, including nested lists, code blocks and block quotes.
+///
+/// Items starting with an indented code block are always indented by the width of their marker and a space,
+/// as Markdown expects that for content starting on the line after the marker.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ListIndentation {
+    /// Indent the content by the width of the marker and the space after it, like `* ` or `10. `
+    MarkerWidth,
+    /// Indent the content by the given amount of columns, like 4 as needed by Python-Markdown and MkDocs,
+    /// with spaces after the marker up to that column, like `*   `.
+    ///
+    /// Markers wider than that are followed by a single space, and no more than four spaces are written
+    /// after a marker, as Markdown would take the content for an indented code block otherwise.
+    Columns(usize),
+    /// Indent the content with tabs, writing a tab after the marker
+    Tab,
+}
+
+/// How the items of ordered lists are numbered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OrderedListNumbering {
+    /// Write the start number of the list for all of its items, like `3.`, `3.`, `3.`
+    Same,
+    /// Number the items one after another, beginning with the start number of the list, like `3.`, `4.`, `5.`
+    Sequential,
+    /// Number the items one after another beginning with 1, whatever the start number of the list is
+    FromOne,
+}
+
+/// How the numbers of the items of ordered lists are padded to the width of the widest number of their list.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ListNumberPadding {
+    /// Write the numbers as they are, like `9.` and `10.`
+    Unpadded,
+    /// Pad the numbers with spaces in front so they are aligned on the right, like ` 9.` and `10.`.
+    ///
+    /// As Markdown allows no more than three spaces in front of a list item, numbers are padded with
+    /// at most three spaces.
+    Spaces,
+    /// Pad the numbers with zeros in front, like `09.` and `10.`
+    Zeros,
+}
+
+/// How the lines of paragraphs are laid out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WrapMode {
+    /// Break lines exactly where the events do, with `SoftBreak`
+    Preserve,
+    /// Break lines at spaces so they don't exceed [`Options::line_width`]
+    Wrap,
+    /// Put each paragraph on a single line
+    Unwrap,
+}
+
 /// The state of the [`cmark_resume()`] and [`cmark_resume_with_options()`] functions.
 /// This does not only allow introspection, but enables the user
 /// to halt the serialization at any time, and resume it later.
@@ -80,6 +287,24 @@ pub struct State<'a> {
     pub newlines_before_start: usize,
     /// The lists and their types for which we have seen a `Event::Start(List(...))` tag
     pub list_stack: Vec<Option<u64>>,
+    /// The marker of the items of each list in `list_stack`, which is the bullet of unordered lists and the
+    /// delimiter after the number of ordered lists
+    pub list_markers: Vec<char>,
+    /// The width the numbers of the items of each list in `list_stack` are padded to, which is 0 for
+    /// unordered lists and lists whose numbers aren't padded
+    pub list_number_widths: Vec<usize>,
+    /// The amount of items of the ordered lists which are about to be written, in the order they start,
+    /// known ahead for [`Options::ordered_list_number_padding`].
+    pub(crate) ordered_list_lengths: VecDeque<usize>,
+    /// The marker of the list that just ended while the next event starts another list, which gets a different
+    /// marker of the same kind if needed so the lists aren't merged when parsed again
+    pub preceding_list_marker: Option<char>,
+    /// The amount of blank lines kept between the blocks of list items with [`ListSpacing::Tight`], as these
+    /// blocks would be merged without them, which leaves their lists loose.
+    pub blank_lines_kept_in_tight_lists: usize,
+    /// True after a block of a list item ended with [`ListSpacing::Tight`] which the next block could continue
+    /// without a blank line in between, like a paragraph.
+    pub(crate) tight_list_block_may_continue: bool,
     /// The computed padding and prefix to print after each newline.
     /// This changes with the level of `BlockQuote` and `List` events.
     pub padding: Vec<Cow<'a, str>>,
@@ -97,19 +322,70 @@ pub struct State<'a> {
     pub last_was_paragraph_start: bool,
     /// True if the next event is a link, image, or footnote.
     pub next_is_link_like: bool,
+    /// True if the next event is inline content other than text, which continues the current line
+    /// with something other than whitespace.
+    pub next_is_inline: bool,
+    /// True if the next event starts a list.
+    pub next_is_list: bool,
+    /// True if the next event starts an indented code block.
+    pub next_is_indented_code_block: bool,
     /// Currently open links
     pub link_stack: Vec<LinkCategory<'a>>,
     /// Currently open images
     pub image_stack: Vec<ImageLink<'a>>,
     /// Keeps track of the last seen heading's id, classes, and attributes
     pub current_heading: Option<Heading<'a>>,
+    /// The rendered content of the current heading if it is written in setext style,
+    /// as its underline depends on the width of the content.
+    pub setext_heading: Option<String>,
+    /// The byte offsets of the hard breaks in [`State::setext_heading`], which are written as HTML instead
+    /// if the heading can't be written in setext style after all.
+    pub(crate) setext_heading_breaks: Vec<usize>,
     /// True whenever between `Start(TableCell)` and `End(TableCell)`
     pub in_table_cell: bool,
+    /// True whenever between `Start(MetadataBlock)` and `End(MetadataBlock)`, whose text is taken literally
+    pub in_metadata_block: bool,
+    /// The closing tags of the containers opened by `Start` events that weren't closed yet, from the outermost
+    /// to the innermost
+    pub open_containers: Vec<TagEnd>,
+    /// The changes made to the events with [`EventValidation::Repair`], in the order they were made
+    pub repairs: Vec<Repair>,
+    /// The amount of open containers outside of each container that was opened around events
+    /// with [`EventValidation::Repair`] and is still open, from the outermost to the innermost.
+    pub(crate) implicit_containers: Vec<usize>,
+    /// The closing tags of the containers whose `Start` event was dropped with [`EventValidation::Repair`]
+    /// and whose `End` event is still to be dropped, along with the amount of open containers outside of them.
+    pub(crate) unwrapped_containers: Vec<(TagEnd, usize)>,
+    /// The rendered cells of each row of the current table, kept until the table ends
+    /// if [`Options::align_table_columns`] is set.
+    pub table_rows: Vec<Vec<String>>,
 
     /// Keeps track of the last seen shortcut/link
     pub current_shortcut_text: Option<String>,
-    /// A list of shortcuts seen so far for later emission
+    /// The label, URI and title of the reference definitions seen so far for later emission, kept in the
+    /// [order](Options::reference_definition_order) they will be written in
     pub shortcuts: Vec<(String, String, String)>,
+    /// The amount of [`State::shortcuts`] collected from images, which are the last ones
+    /// with [`ReferenceDefinitionOrder::LinksBeforeImages`].
+    pub(crate) image_shortcuts: usize,
+    /// The label, URI and title of the reference definitions that were already written before the end of the
+    /// document, as configured with [`Options::reference_definition_placement`], so they aren't written again.
+    pub written_shortcuts: BTreeSet<(String, String, String)>,
+    /// The content written so far to the current line, without padding, while serializing a block that
+    /// directly contains inline elements like a paragraph, and `None` otherwise.
+    pub current_line: Option<String>,
+    /// True while writing the first line of a block that directly contains inline elements,
+    /// where more constructs start a block than on the lines continuing it.
+    pub first_line_of_block: bool,
+    /// True while writing a `Text` event that doesn't follow a backslash in the source it was parsed from,
+    /// which is written without escaping.
+    pub(crate) unescaped_in_source: bool,
+    /// True while writing an event with a range in the source it was parsed from, so the typographic
+    /// punctuation of its text was typed as such.
+    pub(crate) text_in_source: bool,
+    /// The amount of spaces that weren't written yet as they may turn into a line break,
+    /// used with [`WrapMode::Wrap`].
+    pub held_back_spaces: usize,
     /// Index into the `source` bytes of the end of the range corresponding to the last event.
     ///
     /// It's used to see if the current event didn't capture some bytes because of a
@@ -240,10 +516,32 @@ pub struct Options<'a> {
     pub code_block_token: char,
     /// The character to use for unordered list items
     pub list_token: char,
+    /// The characters to use for the items of unordered lists by their nesting depth among all lists,
+    /// like `"-*+"`, starting over after the last one. If empty, [`Options::list_token`] is used for all lists.
+    ///
+    /// A list directly following a list with the same marker uses another one, like `-` instead of `*`
+    /// or `)` instead of `.`, as the lists would be merged into one otherwise.
+    pub nested_list_tokens: &'a str,
     /// The character to use after ordered list numbers (e.g., '.' for `1.`)
     pub ordered_list_token: char,
-    /// Whether to increment the number for each ordered list item
+    /// How to number the items of ordered lists. Defaults to [`OrderedListNumbering::Same`].
+    pub ordered_list_numbering: OrderedListNumbering,
+    /// Whether to increment the number for each ordered list item, as [`OrderedListNumbering::Sequential`] does
+    /// if [`Options::ordered_list_numbering`] is left at [`OrderedListNumbering::Same`]
+    #[deprecated(note = "use `ordered_list_numbering: OrderedListNumbering::Sequential` instead")]
     pub increment_ordered_list_bullets: bool,
+    /// How to pad the numbers of the items of ordered lists. Defaults to [`ListNumberPadding::Unpadded`].
+    ///
+    /// With [`OrderedListNumbering::Sequential`] and [`OrderedListNumbering::FromOne`], this buffers
+    /// ordered lists until their end to learn their widest number. Lists that are still open when the
+    /// serialization is [suspended](Serializer::suspend()), like at the end of each `cmark_resume*` call,
+    /// are padded to the widest number among the items up to there, so `8.` to `10.` are written as
+    /// `8.`, `9.` and `10.` if suspended after `9.`.
+    pub ordered_list_number_padding: ListNumberPadding,
+    /// How to space the items of lists. Defaults to [`ListSpacing::Preserve`].
+    pub list_spacing: ListSpacing,
+    /// How to indent the content of list items. Defaults to [`ListIndentation::MarkerWidth`].
+    pub list_indentation: ListIndentation,
     /// The character to use for emphasis (italic)
     pub emphasis_token: char,
     /// The string to use for strong emphasis (bold)
@@ -256,8 +554,58 @@ pub struct Options<'a> {
     /// you might need this in order to round-trip Markdown byte-for-byte, with knowledge
     /// of whether the parsed documents use `<sub>`/`<sup>` or `^`/`~` instead.
     pub use_html_for_super_sub_script: bool,
+    /// How to write headings. Defaults to [`HeadingStyle::Atx`].
+    pub heading_style: HeadingStyle,
+    /// If `true`, pad the cells of tables so their columns line up, with a space on both sides
+    /// of each pipe and the content placed according to the column alignment.
+    /// This buffers the whole table until its end.
+    pub align_table_columns: bool,
+    /// How to lay out the lines of paragraphs. Defaults to [`WrapMode::Preserve`].
+    pub wrap_mode: WrapMode,
+    /// The maximum width of a line with [`WrapMode::Wrap`], including the padding of block quotes
+    /// and list items. Words longer than that, and constructs which can't be broken like inline code
+    /// or links, can still exceed it.
+    pub line_width: usize,
+    /// The character to use for thematic breaks, one of `-`, `*` or `_`, with `-` used instead of any other
+    pub rule_token: char,
+    /// The amount of tokens of a thematic break, which is at least 3
+    pub rule_token_count: usize,
+    /// The amount of spaces between the tokens of a thematic break, like `1` for `- - -`
+    pub rule_token_spacing: usize,
+    /// If `true`, write control characters and characters that are invisible or easily confused with a space,
+    /// like zero-width and no-break spaces, as numeric character references like `&#160;`.
+    pub numeric_character_references: bool,
+    /// If `true`, write the curly quotes, dashes and ellipses of text as the ASCII characters
+    /// [`ENABLE_SMART_PUNCTUATION`](pulldown_cmark::Options::ENABLE_SMART_PUNCTUATION) turns into them,
+    /// like `--` for `–`. With a source range, the bytes they were parsed from are written instead.
+    pub reverse_smart_punctuation: bool,
+    /// How eagerly to escape characters with a meaning in Markdown. Defaults to [`EscapePolicy::Conservative`].
+    pub escape_policy: EscapePolicy,
+    /// How to write hard line breaks. Defaults to [`HardBreakStyle::TrailingSpaces`].
+    ///
+    /// Table cells, ATX headings and the last line of setext headings can't contain line breaks, which is why
+    /// `<br>` is written there instead.
+    pub hard_break_style: HardBreakStyle,
+    /// The syntax to write links and images in, except for autolinks which are always kept.
+    /// Defaults to [`LinkStyle::Preserve`].
+    pub link_style: LinkStyle,
+    /// Where to write the definitions of reference links and images.
+    /// Defaults to [`ReferenceDefinitionPlacement::EndOfDocument`].
+    pub reference_definition_placement: ReferenceDefinitionPlacement,
+    /// The order to write the definitions of reference links and images in.
+    /// Defaults to [`ReferenceDefinitionOrder::FirstUse`].
+    pub reference_definition_order: ReferenceDefinitionOrder,
+    /// The options the output is going to be parsed with, so that the delimiters of each enabled
+    /// extension are escaped where they appear in text, like `$` with
+    /// [`ENABLE_MATH`](pulldown_cmark::Options::ENABLE_MATH) or `|` with
+    /// [`ENABLE_TABLES`](pulldown_cmark::Options::ENABLE_TABLES).
+    /// Defaults to [`pulldown_cmark::Options::empty()`], i.e. plain CommonMark.
+    pub parser_options: pulldown_cmark::Options,
+    /// How thoroughly the nesting of events is checked. Defaults to [`EventValidation::Unchecked`].
+    pub event_validation: EventValidation,
 }
 
+#[allow(deprecated)]
 const DEFAULT_OPTIONS: Options<'_> = Options {
     newlines_after_headline: 2,
     newlines_after_paragraph: 2,
@@ -272,11 +620,32 @@ const DEFAULT_OPTIONS: Options<'_> = Options {
     code_block_token_count: 4,
     code_block_token: '`',
     list_token: '*',
+    nested_list_tokens: "",
     ordered_list_token: '.',
+    ordered_list_numbering: OrderedListNumbering::Same,
     increment_ordered_list_bullets: false,
+    ordered_list_number_padding: ListNumberPadding::Unpadded,
+    list_spacing: ListSpacing::Preserve,
+    list_indentation: ListIndentation::MarkerWidth,
     emphasis_token: '*',
     strong_token: "**",
     use_html_for_super_sub_script: true,
+    heading_style: HeadingStyle::Atx,
+    align_table_columns: false,
+    wrap_mode: WrapMode::Preserve,
+    line_width: 80,
+    rule_token: '-',
+    rule_token_count: 3,
+    rule_token_spacing: 0,
+    numeric_character_references: false,
+    reverse_smart_punctuation: false,
+    escape_policy: EscapePolicy::Conservative,
+    hard_break_style: HardBreakStyle::TrailingSpaces,
+    link_style: LinkStyle::Preserve,
+    reference_definition_placement: ReferenceDefinitionPlacement::EndOfDocument,
+    reference_definition_order: ReferenceDefinitionOrder::FirstUse,
+    parser_options: pulldown_cmark::Options::empty(),
+    event_validation: EventValidation::Unchecked,
 };
 
 impl Default for Options<'_> {
@@ -305,28 +674,146 @@ impl Options<'_> {
             s.into()
         }
     }
+
+    /// How to number the items of ordered lists, taking the deprecated
+    /// [`Options::increment_ordered_list_bullets`] into account.
+    pub(crate) fn effective_ordered_list_numbering(&self) -> OrderedListNumbering {
+        #[allow(deprecated)]
+        match self.ordered_list_numbering {
+            OrderedListNumbering::Same if self.increment_ordered_list_bullets => OrderedListNumbering::Sequential,
+            numbering => numbering,
+        }
+    }
 }
 
-/// The error returned by [`cmark_resume_with_options()`] and
-/// [`cmark_resume_with_source_range_and_options()`].
+/// The error returned by [`cmark_resume_with_options()`],
+/// [`cmark_resume_with_source_range_and_options()`] and their `io` counterparts.
 #[derive(Debug)]
 pub enum Error {
     /// Formatting to the output writer failed
     FormatFailed(fmt::Error),
+    /// Writing to the [`std::io::Write`] sink failed
+    Io(std::io::Error),
     /// An event was encountered that cannot be produced by valid markdown
-    UnexpectedEvent,
+    UnexpectedEvent(Box<UnexpectedEvent>),
+    /// The events ended while the containers with the given closing tags were still open,
+    /// from the outermost to the innermost
+    UnclosedContainers(Vec<TagEnd>),
+    /// An item of an ordered list would be numbered with the given number, which has more than the nine digits
+    /// Markdown allows
+    ListNumberOutOfRange(u64),
+}
+
+/// An event that can't be produced by parsing valid Markdown, along with where it was encountered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnexpectedEvent {
+    /// The zero-based index of the event among those passed to the [`Serializer`], or the function that created it
+    pub index: usize,
+    /// The offending event
+    pub event: Event<'static>,
+    /// The closing tag of the innermost open container, which is the `End` event expected before anything
+    /// that can't be within it
+    pub expected: Option<TagEnd>,
+    /// The closing tags of all open containers, from the outermost to the innermost
+    pub open_containers: Vec<TagEnd>,
+}
+
+impl Error {
+    /// Create an [`Error::UnexpectedEvent`] for `event`, which can't follow the events serialized into `state`.
+    ///
+    /// Its index is set by the [`Serializer`], which counts the events.
+    pub(crate) fn unexpected_event(event: &Event<'_>, state: &State<'_>) -> Self {
+        Self::UnexpectedEvent(Box::new(UnexpectedEvent {
+            index: 0,
+            event: event.clone().into_static(),
+            expected: state.open_containers.last().copied(),
+            open_containers: state.open_containers.clone(),
+        }))
+    }
+}
+
+impl fmt::Display for UnexpectedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unexpected event {} while reconstructing Markdown: ", self.index)?;
+        write_event_readably(f, &self.event)?;
+        match self.expected {
+            Some(expected) => write!(
+                f,
+                ", expected End({:?}) to close the open containers {:?}",
+                expected, self.open_containers
+            ),
+            None => f.write_str(", with no open containers"),
+        }
+    }
+}
+
+/// Write `event` readably, with its text as a string and its tag by the name of its closing tag.
+fn write_event_readably(f: &mut fmt::Formatter<'_>, event: &Event<'_>) -> fmt::Result {
+    let (kind, text) = match event {
+        Event::Start(tag) => return write!(f, "Start({:?})", tag.to_end()),
+        Event::End(tag) => return write!(f, "End({tag:?})"),
+        Event::TaskListMarker(checked) => return write!(f, "TaskListMarker({checked})"),
+        Event::SoftBreak | Event::HardBreak | Event::Rule => return write!(f, "{event:?}"),
+        Event::Text(text) => ("Text", text),
+        Event::Code(text) => ("Code", text),
+        Event::InlineMath(text) => ("InlineMath", text),
+        Event::DisplayMath(text) => ("DisplayMath", text),
+        Event::Html(text) => ("Html", text),
+        Event::InlineHtml(text) => ("InlineHtml", text),
+        Event::FootnoteReference(label) => ("FootnoteReference", label),
+    };
+    write!(f, "{kind}({:?})", &**text)
+}
+
+/// A change made to a malformed stream of events with [`EventValidation::Repair`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Repair {
+    /// The zero-based index of the event which caused the repair, or the amount of events for repairs
+    /// made when the serialization finished
+    pub index: usize,
+    /// What was changed
+    pub kind: RepairKind,
+}
+
+/// The kinds of [`Repair`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RepairKind {
+    /// An `End` event was inserted to close the container with the given closing tag, which was left open
+    Closed(TagEnd),
+    /// A `Start` event was inserted to open the container with the given closing tag around an event
+    /// that can only appear within it, like a paragraph around inline events outside of blocks
+    Opened(TagEnd),
+    /// The container with the given closing tag was replaced by its content, as it can't appear anywhere
+    /// within the open containers, like a link within a link
+    Unwrapped(TagEnd),
+    /// An `End` event with the given tag was dropped as it doesn't close any open container
+    DroppedEnd(TagEnd),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::FormatFailed(e) => e.fmt(f),
-            Self::UnexpectedEvent => f.write_str("Unexpected event while reconstructing Markdown"),
+            Self::Io(e) => e.fmt(f),
+            Self::UnexpectedEvent(e) => e.fmt(f),
+            Self::UnclosedContainers(open) => write!(f, "The events ended before closing the containers {open:?}"),
+            Self::ListNumberOutOfRange(number) => write!(
+                f,
+                "The list item number {number} has more than the {} digits Markdown allows",
+                decimal_width(MAX_LIST_NUMBER)
+            ),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<fmt::Error> for Error {
     fn from(e: fmt::Error) -> Self {
@@ -334,6 +821,12 @@ impl From<fmt::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 /// As [`cmark_with_options()`], but with default [`Options`].
 pub fn cmark<'a, I, E, F>(events: I, mut formatter: F) -> Result<State<'a>, Error>
 where
@@ -361,8 +854,9 @@ where
     E: Borrow<Event<'a>>,
     F: fmt::Write,
 {
-    let state = cmark_resume_with_options(events, &mut formatter, Default::default(), options)?;
-    state.finalize(formatter)
+    let mut serializer = Serializer::new(&mut formatter, options);
+    serializer.extend(events)?;
+    Ok(serializer.finish()?.1)
 }
 
 /// Serialize a stream of [pulldown-cmark-Events][Event] into a string-backed buffer.
@@ -394,19 +888,9 @@ where
     E: Borrow<Event<'a>>,
     F: fmt::Write,
 {
-    let mut state = state.unwrap_or_default();
-    let mut events = events.peekable();
-    while let Some(event) = events.next() {
-        state.next_is_link_like = matches!(
-            events.peek().map(Borrow::borrow),
-            Some(
-                Event::Start(Tag::Link { .. } | Tag::Image { .. } | Tag::FootnoteDefinition(..))
-                    | Event::FootnoteReference(..)
-            )
-        );
-        cmark_resume_one_event(event, &mut formatter, &mut state, &options)?;
-    }
-    Ok(state)
+    let mut serializer = Serializer::resume(&mut formatter, state.unwrap_or_default(), options);
+    serializer.extend(events)?;
+    Ok(serializer.suspend()?.1)
 }
 
 fn cmark_resume_one_event<'a, E, F>(
@@ -418,6 +902,340 @@ fn cmark_resume_one_event<'a, E, F>(
 where
     E: Borrow<Event<'a>>,
     F: fmt::Write,
+{
+    let event = event.borrow();
+    route_one_event(event, formatter, state, options)?;
+    match event {
+        Event::Start(tag) => state.open_containers.push(tag.to_end()),
+        Event::End(_) => {
+            state.open_containers.pop();
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Serialize `event` into the buffer of a setext heading or aligned table cell, if one is open.
+fn route_one_event<'a, F>(
+    event: &Event<'a>,
+    formatter: &mut F,
+    state: &mut State<'a>,
+    options: &Options<'_>,
+) -> Result<(), Error>
+where
+    F: fmt::Write,
+{
+    if let (Some(heading), false) = (
+        state.setext_heading.as_mut(),
+        matches!(event, Event::End(TagEnd::Heading(_))),
+    ) {
+        let mut content = std::mem::take(heading);
+        if *event == Event::HardBreak {
+            state.setext_heading_breaks.push(content.len());
+        }
+        let res = cmark_resume_one_tracked_event(event, &mut content, state, options);
+        if let Some(heading) = state.setext_heading.as_mut() {
+            *heading = content;
+        }
+        return res;
+    }
+    if options.align_table_columns && state.in_table_cell && event != &Event::End(TagEnd::TableCell) {
+        let mut cell = state
+            .table_rows
+            .last_mut()
+            .and_then(Vec::pop)
+            .ok_or_else(|| Error::unexpected_event(event, state))?;
+        let res = cmark_resume_one_tracked_event(event, &mut cell, state, options);
+        match state.table_rows.last_mut() {
+            Some(row) => row.push(cell),
+            None => return Err(Error::unexpected_event(event, state)),
+        }
+        return res;
+    }
+    cmark_resume_one_tracked_event(event, formatter, state, options)
+}
+
+/// Serialize `event`, keeping track of [`State::current_line`].
+fn cmark_resume_one_tracked_event<'a, F>(
+    event: &Event<'a>,
+    formatter: &mut F,
+    state: &mut State<'a>,
+    options: &Options<'_>,
+) -> Result<(), Error>
+where
+    F: fmt::Write,
+{
+    let mut formatter = LineTracker::new(formatter, state);
+    if state.tight_list_block_may_continue {
+        keep_blank_line_in_tight_list(event, state, options);
+    }
+    if state.held_back_spaces > 0 {
+        write_or_drop_held_back_spaces(event, &mut formatter, state, options)?;
+    }
+    if let (Event::Start(Tag::Heading { level, .. }), ReferenceDefinitionPlacement::EndOfSection(section_level)) =
+        (event, options.reference_definition_placement)
+    {
+        if *level <= section_level && state.is_at_top_level() {
+            state.write_shortcuts_block(&mut formatter)?;
+        }
+    }
+    let ends_indented_code = state.code_block == Some(CodeBlockKind::Indented);
+    let mut res = write_event(event, &mut formatter, state, options);
+    if options.list_spacing != ListSpacing::Preserve {
+        space_list_items(event, ends_indented_code, state, options);
+    }
+    state.first_line_of_block = match event {
+        Event::Start(tag) if holds_inline_content(tag) => true,
+        Event::Start(tag) if !is_inline(&tag.to_end()) => false,
+        Event::End(tag) if !is_inline(tag) => false,
+        _ => state.first_line_of_block && !formatter.wrote_newline(),
+    };
+    if let (Event::End(tag), ReferenceDefinitionPlacement::AfterBlock) = (event, options.reference_definition_placement)
+    {
+        if res.is_ok() && ends_top_level_block(tag) && state.is_at_top_level() {
+            res = state.write_shortcuts_block(&mut formatter).map_err(Into::into);
+        }
+    }
+    state.current_line = match event {
+        Event::Start(tag) if holds_inline_content(tag) => Some(String::new()),
+        Event::Start(tag) if !is_inline(&tag.to_end()) => None,
+        Event::End(tag) if !is_inline(tag) => None,
+        _ => formatter.into_line(),
+    };
+    res
+}
+
+/// Set the newlines after `event` as configured with [`Options::list_spacing`], if it ends a list item
+/// or a block directly within one. `ends_indented_code` is `true` if `event` ends an indented code block.
+fn space_list_items(event: &Event<'_>, ends_indented_code: bool, state: &mut State<'_>, options: &Options<'_>) {
+    let in_item = match event {
+        Event::End(tag) if !is_inline(tag) => matches!(state.open_containers.as_slice(), [.., TagEnd::Item, _]),
+        Event::Rule => state.open_containers.last() == Some(&TagEnd::Item),
+        _ => false,
+    };
+    match (options.list_spacing, event) {
+        (ListSpacing::Loose, Event::End(TagEnd::Item)) => state.newlines_before_start = 2,
+        (ListSpacing::Tight, Event::End(TagEnd::Item)) => {
+            state.newlines_before_start = 1;
+            state.tight_list_block_may_continue = false;
+        }
+        (ListSpacing::Tight, Event::End(tag)) if in_item => {
+            state.newlines_before_start = 1;
+            state.tight_list_block_may_continue = ends_indented_code
+                || matches!(
+                    tag,
+                    TagEnd::Paragraph
+                        | TagEnd::BlockQuote(_)
+                        | TagEnd::List(_)
+                        | TagEnd::Table
+                        | TagEnd::HtmlBlock
+                        | TagEnd::DefinitionList
+                        | TagEnd::FootnoteDefinition
+                );
+        }
+        (ListSpacing::Tight, Event::Rule) if in_item => state.newlines_before_start = 1,
+        _ => {}
+    }
+}
+
+/// Keep a blank line in front of `event` with [`ListSpacing::Tight`] if it would continue the block before it
+/// within a list item otherwise, which is done unless `event` always starts a new block.
+fn keep_blank_line_in_tight_list(event: &Event<'_>, state: &mut State<'_>, options: &Options<'_>) {
+    state.tight_list_block_may_continue = false;
+    let starts_new_block = match event {
+        Event::End(_) => true,
+        Event::Start(Tag::List(start)) => matches!(start, None | Some(1)),
+        Event::Start(Tag::BlockQuote(_) | Tag::CodeBlock(pulldown_cmark::CodeBlockKind::Fenced(_))) => true,
+        Event::Start(Tag::Heading { .. }) => options.heading_style != HeadingStyle::Setext,
+        Event::Rule => rule_token(options) != '-' || options.rule_token_spacing != 0,
+        _ => false,
+    };
+    if !starts_new_block {
+        state.set_minimum_newlines_before_start(2);
+        state.blank_lines_kept_in_tight_lists += 1;
+    }
+}
+
+/// Returns `true` if `tag` is an inline element, which can only appear within blocks.
+fn is_inline(tag: &TagEnd) -> bool {
+    matches!(
+        tag,
+        TagEnd::Emphasis
+            | TagEnd::Strong
+            | TagEnd::Strikethrough
+            | TagEnd::Superscript
+            | TagEnd::Subscript
+            | TagEnd::Link
+            | TagEnd::Image
+    )
+}
+
+/// The marker of the items of a list at `depth` among the open lists, as configured in `options`.
+fn list_marker(ordered: bool, depth: usize, options: &Options<'_>) -> char {
+    if ordered {
+        return options.ordered_list_token;
+    }
+    let tokens = options.nested_list_tokens.chars().count();
+    options
+        .nested_list_tokens
+        .chars()
+        .nth(depth % tokens.max(1))
+        .unwrap_or(options.list_token)
+}
+
+/// The largest number of an ordered list item, as Markdown allows no more than nine digits.
+const MAX_LIST_NUMBER: u64 = 999_999_999;
+
+/// The amount of decimal digits of `number`.
+fn decimal_width(number: u64) -> usize {
+    number.checked_ilog10().map_or(1, |log| log as usize + 1)
+}
+
+/// Another list marker of the same kind as `marker`, for a list following a list with that marker.
+fn alternative_list_marker(marker: char) -> char {
+    match marker {
+        '.' => ')',
+        ')' => '.',
+        '*' => '-',
+        _ => '*',
+    }
+}
+
+/// Close a collapsed or shortcut reference link or image with `closing`, or as full reference link with
+/// [`LinkStyle::Reference`], and remember its definition. Its label is the text seen since it started.
+fn close_shortcut_link<F>(
+    uri: &str,
+    title: &str,
+    is_image: bool,
+    closing: &str,
+    formatter: &mut F,
+    state: &mut State<'_>,
+    options: &Options<'_>,
+) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    let Some(label) = state.current_shortcut_text.take() else {
+        return formatter.write_str(closing);
+    };
+    if options.link_style == LinkStyle::Reference {
+        write!(formatter, "][{label}]")?;
+    } else {
+        formatter.write_str(closing)?;
+    }
+    state.add_shortcut(label, uri, title, is_image, options);
+    Ok(())
+}
+
+/// The type a link or image of `link_type` is written as with [`Options::link_style`], along with its label.
+fn link_type_for_style<'a>(
+    link_type: LinkType,
+    id: &CowStr<'a>,
+    uri: &str,
+    title: &str,
+    state: &State<'_>,
+    options: &Options<'_>,
+) -> (LinkType, CowStr<'a>) {
+    match (options.link_style, link_type) {
+        (LinkStyle::Preserve, _) | (_, LinkType::Autolink | LinkType::Email) => (link_type, id.clone()),
+        (LinkStyle::Inline, _) => (LinkType::Inline, id.clone()),
+        (LinkStyle::Reference, LinkType::Reference | LinkType::Collapsed | LinkType::Shortcut)
+            if !state.label_is_taken(id, uri, title) =>
+        {
+            (link_type, id.clone())
+        }
+        (LinkStyle::Reference, _) => (LinkType::Reference, state.reference_label(uri, title).into()),
+    }
+}
+
+/// Returns `true` if the reference labels `a` and `b` match, which they do regardless of case and
+/// consecutive whitespace.
+pub(crate) fn labels_match(a: &str, b: &str) -> bool {
+    let normalized = |label: &str| label.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    normalized(a) == normalized(b)
+}
+
+/// Returns `true` if `tag` ends a block which can appear on its own at the top level of a document.
+fn ends_top_level_block(tag: &TagEnd) -> bool {
+    matches!(
+        tag,
+        TagEnd::Paragraph
+            | TagEnd::Heading(_)
+            | TagEnd::BlockQuote(_)
+            | TagEnd::CodeBlock
+            | TagEnd::HtmlBlock
+            | TagEnd::List(_)
+            | TagEnd::FootnoteDefinition
+            | TagEnd::DefinitionList
+            | TagEnd::Table
+            | TagEnd::MetadataBlock(_)
+    )
+}
+
+/// Returns `true` if `tag` is a block that can directly contain inline elements.
+fn holds_inline_content(tag: &Tag<'_>) -> bool {
+    matches!(
+        tag,
+        Tag::Paragraph
+            | Tag::Heading { .. }
+            | Tag::Item
+            | Tag::TableCell
+            | Tag::DefinitionListTitle
+            | Tag::DefinitionListDefinition
+    )
+}
+
+/// Write the [`State::held_back_spaces`] in front of `event`, possibly as line break,
+/// or drop them if `event` ends the line anyway.
+fn write_or_drop_held_back_spaces<F>(
+    event: &Event<'_>,
+    formatter: &mut LineTracker<'_, F>,
+    state: &mut State<'_>,
+    options: &Options<'_>,
+) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    match event {
+        // These decide for themselves.
+        Event::Text(_) | Event::SoftBreak => Ok(()),
+        Event::Code(text) | Event::InlineMath(text) => {
+            write_held_back_spaces(formatter, state, options, text_width(text) + 2, false)
+        }
+        // Inline HTML starting a line could open an HTML block instead, like `<div>`.
+        Event::InlineHtml(html) => write_held_back_spaces(
+            formatter,
+            state,
+            options,
+            text_width(html.split('\n').next().unwrap_or_default()),
+            starts_like_block(html),
+        ),
+        Event::FootnoteReference(name) => {
+            write_held_back_spaces(formatter, state, options, text_width(name) + 3, false)
+        }
+        Event::Start(tag) if is_inline(&tag.to_end()) => write_held_back_spaces(formatter, state, options, 1, false),
+        Event::End(tag) if is_inline(tag) => {
+            write!(
+                formatter,
+                "{}",
+                Repeated(' ', std::mem::take(&mut state.held_back_spaces))
+            )
+        }
+        _ => {
+            state.held_back_spaces = 0;
+            Ok(())
+        }
+    }
+}
+
+fn write_event<'a, F>(
+    event: &Event<'a>,
+    formatter: &mut LineTracker<'_, F>,
+    state: &mut State<'a>,
+    options: &Options<'_>,
+) -> Result<(), Error>
+where
+    F: fmt::Write,
 {
     use pulldown_cmark::{Event::*, Tag::*};
 
@@ -426,11 +1244,18 @@ where
     let last_was_paragraph_start = state.last_was_paragraph_start;
     state.last_was_paragraph_start = false;
 
-    let res = match event.borrow() {
+    let res = match event {
         Rule => {
+            if formatter.line().is_some_and(|line| !line.is_empty()) {
+                state.set_minimum_newlines_before_start(1);
+            }
+            // A rule like `---` directly below a line of text would underline it as setext heading instead.
+            if rule_token(options) == '-' && options.rule_token_spacing == 0 && state.newlines_before_start != 0 {
+                state.set_minimum_newlines_before_start(2);
+            }
             consume_newlines(formatter, state)?;
             state.set_minimum_newlines_before_start(options.newlines_after_rule);
-            formatter.write_str("---")
+            write_rule(formatter, options)
         }
         Code(text) => {
             if let Some(shortcut_text) = state.current_shortcut_text.as_mut() {
@@ -476,7 +1301,31 @@ where
         }
         Start(tag) => {
             if let List(list_type) = tag {
-                state.list_stack.push(*list_type);
+                let marker = list_marker(list_type.is_some(), state.list_stack.len(), options);
+                let marker = match state.preceding_list_marker.take() {
+                    Some(preceding) if preceding == marker => alternative_list_marker(marker),
+                    _ => marker,
+                };
+                state.list_markers.push(marker);
+                let list_type = match (list_type, options.effective_ordered_list_numbering()) {
+                    (Some(_), OrderedListNumbering::FromOne) => Some(1),
+                    _ => *list_type,
+                };
+                let number_width = match (list_type, options.ordered_list_number_padding) {
+                    (Some(first), ListNumberPadding::Spaces | ListNumberPadding::Zeros) => {
+                        let last = match options.effective_ordered_list_numbering() {
+                            OrderedListNumbering::Same => first,
+                            _ => {
+                                let items = state.ordered_list_lengths.pop_front().unwrap_or(1);
+                                first.saturating_add(items.saturating_sub(1) as u64)
+                            }
+                        };
+                        decimal_width(last)
+                    }
+                    _ => 0,
+                };
+                state.list_number_widths.push(number_width);
+                state.list_stack.push(list_type);
                 if state.list_stack.len() > 1 {
                     state.set_minimum_newlines_before_start(options.newlines_after_rest);
                 }
@@ -487,33 +1336,61 @@ where
                 Item => {
                     // lazy lists act like paragraphs with no event
                     state.last_was_paragraph_start = true;
-                    match state.list_stack.last_mut() {
-                        Some(inner) => {
-                            state.padding.push(list_item_padding_of(*inner));
-                            match inner {
-                                Some(n) => {
-                                    let bullet_number = *n;
-                                    if options.increment_ordered_list_bullets {
-                                        *n += 1;
-                                    }
-                                    write!(formatter, "{}{} ", bullet_number, options.ordered_list_token)
+                    let marker = match state.list_stack.last_mut() {
+                        Some(Some(n)) => {
+                            let number = *n;
+                            if number > MAX_LIST_NUMBER {
+                                return Err(Error::ListNumberOutOfRange(number));
+                            }
+                            if options.effective_ordered_list_numbering() != OrderedListNumbering::Same {
+                                *n += 1;
+                            }
+                            let delimiter = state.list_markers.last().copied();
+                            let delimiter = delimiter.unwrap_or(options.ordered_list_token);
+                            let width = state.list_number_widths.last().copied().unwrap_or(0);
+                            match options.ordered_list_number_padding {
+                                ListNumberPadding::Unpadded => format!("{number}{delimiter}"),
+                                ListNumberPadding::Spaces => {
+                                    let width = width.min(decimal_width(number) + 3);
+                                    format!("{number:>width$}{delimiter}")
                                 }
-                                None => write!(formatter, "{} ", options.list_token),
+                                ListNumberPadding::Zeros => format!("{number:0width$}{delimiter}"),
                             }
                         }
-                        None => Ok(()),
-                    }
+                        Some(None) => state
+                            .list_markers
+                            .last()
+                            .copied()
+                            .unwrap_or(options.list_token)
+                            .to_string(),
+                        None => return Ok(()),
+                    };
+                    let (marker, padding) = list_item_marker_and_padding(&marker, state, options);
+                    state.padding.push(padding);
+                    formatter.write_str(&marker)
                 }
                 Table(alignments) => {
                     state.table_alignments = alignments.iter().map(From::from).collect();
                     Ok(())
                 }
-                TableHead => Ok(()),
-                TableRow => Ok(()),
+                TableHead | TableRow => {
+                    if options.align_table_columns {
+                        state.table_rows.push(Vec::new());
+                    }
+                    Ok(())
+                }
                 TableCell => {
                     state.text_for_header = Some(String::new());
                     state.in_table_cell = true;
-                    formatter.write_char('|')
+                    if options.align_table_columns {
+                        match state.table_rows.last_mut() {
+                            Some(row) => row.push(String::new()),
+                            None => return Err(Error::unexpected_event(event, state)),
+                        }
+                        Ok(())
+                    } else {
+                        formatter.write_char('|')
+                    }
                 }
                 Link {
                     link_type,
@@ -521,6 +1398,7 @@ where
                     title,
                     id,
                 } => {
+                    let (link_type, id) = link_type_for_style(*link_type, id, dest_url, title, state, options);
                     state.link_stack.push(match link_type {
                         LinkType::Autolink | LinkType::Email => {
                             formatter.write_char('<')?;
@@ -566,6 +1444,7 @@ where
                     title,
                     id,
                 } => {
+                    let (link_type, id) = link_type_for_style(*link_type, id, dest_url, title, state, options);
                     state.image_stack.push(match link_type {
                         LinkType::Reference => ImageLink::Reference {
                             uri: dest_url.clone().into(),
@@ -610,7 +1489,7 @@ where
                     attrs,
                 } => {
                     if state.current_heading.is_some() {
-                        return Err(Error::UnexpectedEvent);
+                        return Err(Error::unexpected_event(event, state));
                     }
                     state.current_heading = Some(self::Heading {
                         id: id.as_ref().map(|id| id.clone().into()),
@@ -620,8 +1499,14 @@ where
                             .map(|(k, v)| (k.clone().into(), v.as_ref().map(|val| val.clone().into())))
                             .collect(),
                     });
-                    // Write '#', '##', '###', etc. based on the heading level.
-                    write!(formatter, "{} ", Repeated('#', *level as usize))
+                    if options.heading_style == HeadingStyle::Setext && *level <= HeadingLevel::H2 {
+                        // The underline depends on the width of the content, which is collected first.
+                        state.setext_heading = Some(String::new());
+                        Ok(())
+                    } else {
+                        // Write '#', '##', '###', etc. based on the heading level.
+                        write!(formatter, "{} ", Repeated('#', *level as usize))
+                    }
                 }
                 BlockQuote(kind) => {
                     let every_line_padding = " > ";
@@ -652,22 +1537,27 @@ where
                     if consumed_newlines {
                         formatter.write_str("    ")
                     } else {
-                        write_padded_newline(formatter, &state)
+                        write_padded_newline(formatter, state)
                     }
                 }
                 CodeBlock(pulldown_cmark::CodeBlockKind::Fenced(info)) => {
                     state.code_block = Some(CodeBlockKind::Fenced);
                     if !consumed_newlines {
-                        write_padded_newline(formatter, &state)?;
+                        write_padded_newline(formatter, state)?;
                     }
 
                     let fence = Repeated(options.code_block_token, options.code_block_token_count);
                     write!(formatter, "{fence}{info}")?;
-                    write_padded_newline(formatter, &state)
+                    write_padded_newline(formatter, state)
                 }
                 HtmlBlock => Ok(()),
-                MetadataBlock(MetadataBlockKind::YamlStyle) => formatter.write_str("---\n"),
-                MetadataBlock(MetadataBlockKind::PlusesStyle) => formatter.write_str("+++\n"),
+                MetadataBlock(kind) => {
+                    state.in_metadata_block = true;
+                    formatter.write_str(match kind {
+                        MetadataBlockKind::YamlStyle => "---\n",
+                        MetadataBlockKind::PlusesStyle => "+++\n",
+                    })
+                }
                 List(_) => Ok(()),
                 Strikethrough => formatter.write_str("~~"),
                 DefinitionList => Ok(()),
@@ -699,63 +1589,39 @@ where
             TagEnd::Link => match if let Some(link_cat) = state.link_stack.pop() {
                 link_cat
             } else {
-                return Err(Error::UnexpectedEvent);
+                return Err(Error::unexpected_event(event, state));
             } {
                 LinkCategory::AngleBracketed => formatter.write_char('>'),
                 LinkCategory::Reference { uri, title, id } => {
-                    state
-                        .shortcuts
-                        .push((id.to_string(), uri.to_string(), title.to_string()));
+                    state.add_shortcut(id.to_string(), &uri, &title, false, options);
                     formatter.write_str("][")?;
                     formatter.write_str(&id)?;
                     formatter.write_char(']')
                 }
                 LinkCategory::Collapsed { uri, title } => {
-                    if let Some(shortcut_text) = state.current_shortcut_text.take() {
-                        state
-                            .shortcuts
-                            .push((shortcut_text, uri.to_string(), title.to_string()));
-                    }
-                    formatter.write_str("][]")
+                    close_shortcut_link(&uri, &title, false, "][]", formatter, state, options)
                 }
                 LinkCategory::Shortcut { uri, title } => {
-                    if let Some(shortcut_text) = state.current_shortcut_text.take() {
-                        state
-                            .shortcuts
-                            .push((shortcut_text, uri.to_string(), title.to_string()));
-                    }
-                    formatter.write_char(']')
+                    close_shortcut_link(&uri, &title, false, "]", formatter, state, options)
                 }
                 LinkCategory::Other { uri, title } => close_link(&uri, &title, formatter, LinkType::Inline),
             },
             TagEnd::Image => match if let Some(img_link) = state.image_stack.pop() {
                 img_link
             } else {
-                return Err(Error::UnexpectedEvent);
+                return Err(Error::unexpected_event(event, state));
             } {
                 ImageLink::Reference { uri, title, id } => {
-                    state
-                        .shortcuts
-                        .push((id.to_string(), uri.to_string(), title.to_string()));
+                    state.add_shortcut(id.to_string(), &uri, &title, true, options);
                     formatter.write_str("][")?;
                     formatter.write_str(&id)?;
                     formatter.write_char(']')
                 }
                 ImageLink::Collapsed { uri, title } => {
-                    if let Some(shortcut_text) = state.current_shortcut_text.take() {
-                        state
-                            .shortcuts
-                            .push((shortcut_text, uri.to_string(), title.to_string()));
-                    }
-                    formatter.write_str("][]")
+                    close_shortcut_link(&uri, &title, true, "][]", formatter, state, options)
                 }
                 ImageLink::Shortcut { uri, title } => {
-                    if let Some(shortcut_text) = state.current_shortcut_text.take() {
-                        state
-                            .shortcuts
-                            .push((shortcut_text, uri.to_string(), title.to_string()));
-                    }
-                    formatter.write_char(']')
+                    close_shortcut_link(&uri, &title, true, "]", formatter, state, options)
                 }
                 ImageLink::Other { uri, title } => {
                     close_link(uri.as_ref(), title.as_ref(), formatter, LinkType::Inline)
@@ -763,40 +1629,48 @@ where
             },
             TagEnd::Emphasis => formatter.write_char(options.emphasis_token),
             TagEnd::Strong => formatter.write_str(options.strong_token),
-            TagEnd::Heading(_) => {
-                let Some(self::Heading {
-                    id,
-                    classes,
-                    attributes,
-                }) = state.current_heading.take()
-                else {
-                    return Err(Error::UnexpectedEvent);
+            TagEnd::Heading(level) => {
+                let Some(heading) = state.current_heading.take() else {
+                    return Err(Error::unexpected_event(event, state));
                 };
-                let emit_braces = id.is_some() || !classes.is_empty() || !attributes.is_empty();
-                if emit_braces {
-                    formatter.write_str(" {")?;
-                }
-                if let Some(id_str) = id {
-                    formatter.write_char(' ')?;
-                    formatter.write_char('#')?;
-                    formatter.write_str(&id_str)?;
-                }
-                for class in &classes {
-                    formatter.write_char(' ')?;
-                    formatter.write_char('.')?;
-                    formatter.write_str(class)?;
-                }
-                for (key, val) in &attributes {
-                    formatter.write_char(' ')?;
-                    formatter.write_str(key)?;
-                    if let Some(val) = val {
-                        formatter.write_char('=')?;
-                        formatter.write_str(val)?;
+                let level = *level as usize;
+                let breaks = std::mem::take(&mut state.setext_heading_breaks);
+                match state.setext_heading.take() {
+                    Some(mut content) => {
+                        write_heading_attributes(&mut content, &heading)?;
+                        let padding = Padding(&state.padding).to_string();
+                        let lines: Vec<_> = content
+                            .split('\n')
+                            .map(|line| line.strip_prefix(padding.as_str()).unwrap_or(line))
+                            .collect();
+                        let can_be_setext = lines.iter().all(|line| {
+                            line.split(' ')
+                                .next()
+                                .is_some_and(|word| !word.is_empty() && !starts_like_block(word))
+                        });
+                        if can_be_setext {
+                            let width = lines.iter().map(|line| text_width(line)).max().unwrap_or_default();
+                            formatter.write_str(&content)?;
+                            write_padded_newline(formatter, state)?;
+                            let underline = if level == 1 { '=' } else { '-' };
+                            write!(formatter, "{}", Repeated(underline, width.max(1)))?;
+                        } else {
+                            // Lines of ATX headings can't be broken.
+                            for start in breaks.into_iter().rev() {
+                                let newline = start + content[start..].find('\n').unwrap_or(content.len() - start);
+                                let end = (newline + 1 + padding.len()).min(content.len());
+                                content.replace_range(start..end, "<br>");
+                            }
+                            let content = content.replace(&format!("\n{padding}"), " ");
+                            write!(formatter, "{} {content}", Repeated('#', level))?;
+                        }
+                    }
+                    None => {
+                        if options.heading_style == HeadingStyle::ClosedAtx {
+                            write!(formatter, " {}", Repeated('#', level))?;
+                        }
+                        write_heading_attributes(formatter, &heading)?;
                     }
-                }
-                if emit_braces {
-                    formatter.write_char(' ')?;
-                    formatter.write_char('}')?;
                 }
                 state.set_minimum_newlines_before_start(options.newlines_after_headline);
                 Ok(())
@@ -808,7 +1682,7 @@ where
             TagEnd::CodeBlock => {
                 state.set_minimum_newlines_before_start(options.newlines_after_codeblock);
                 if last_was_text_without_trailing_newline {
-                    write_padded_newline(formatter, &state)?;
+                    write_padded_newline(formatter, state)?;
                 }
                 match state.code_block {
                     Some(CodeBlockKind::Fenced) => {
@@ -827,18 +1701,22 @@ where
                 state.set_minimum_newlines_before_start(options.newlines_after_htmlblock);
                 Ok(())
             }
-            TagEnd::MetadataBlock(MetadataBlockKind::PlusesStyle) => {
+            TagEnd::MetadataBlock(kind) => {
+                state.in_metadata_block = false;
                 state.set_minimum_newlines_before_start(options.newlines_after_metadata);
-                formatter.write_str("+++\n")
-            }
-            TagEnd::MetadataBlock(MetadataBlockKind::YamlStyle) => {
-                state.set_minimum_newlines_before_start(options.newlines_after_metadata);
-                formatter.write_str("---\n")
+                formatter.write_str(match kind {
+                    MetadataBlockKind::YamlStyle => "---\n",
+                    MetadataBlockKind::PlusesStyle => "+++\n",
+                })
             }
             TagEnd::Table => {
                 state.set_minimum_newlines_before_start(options.newlines_after_table);
+                if options.align_table_columns {
+                    write_aligned_table(formatter, state)?;
+                }
                 state.table_alignments.clear();
                 state.table_headers.clear();
+                state.table_rows.clear();
                 Ok(())
             }
             TagEnd::TableCell => {
@@ -848,40 +1726,19 @@ where
                 state.in_table_cell = false;
                 Ok(())
             }
+            TagEnd::TableRow | TagEnd::TableHead if options.align_table_columns => Ok(()),
             t @ (TagEnd::TableRow | TagEnd::TableHead) => {
                 state.set_minimum_newlines_before_start(options.newlines_after_rest);
                 formatter.write_char('|')?;
 
                 if let TagEnd::TableHead = t {
-                    write_padded_newline(formatter, &state)?;
+                    write_padded_newline(formatter, state)?;
                     for (alignment, name) in state.table_alignments.iter().zip(state.table_headers.iter()) {
                         formatter.write_char('|')?;
-                        // NOTE: For perfect counting, count grapheme clusters.
-                        // The reason this is not done is to avoid the dependency.
-
-                        // The minimum width of the column so that we can represent its alignment.
-                        let min_width = match alignment {
-                            // Must at least represent `-`.
-                            Alignment::None => 1,
-                            // Must at least represent `:-` or `-:`
-                            Alignment::Left | Alignment::Right => 2,
-                            // Must at least represent `:-:`
-                            Alignment::Center => 3,
-                        };
-                        let length = name.chars().count().max(min_width);
-                        let last_minus_one = length.saturating_sub(1);
-                        for c in 0..length {
-                            formatter.write_char(
-                                if (c == 0 && (alignment == &Alignment::Center || alignment == &Alignment::Left))
-                                    || (c == last_minus_one
-                                        && (alignment == &Alignment::Center || alignment == &Alignment::Right))
-                                {
-                                    ':'
-                                } else {
-                                    '-'
-                                },
-                            )?;
-                        }
+                        // NOTE: Counts grapheme clusters and their display width only with the
+                        // `unicode-width` feature, to keep the default build free of dependencies.
+                        let length = text_width(name).max(alignment.min_delimiter_width());
+                        write_table_delimiter(formatter, *alignment, length)?;
                     }
                     formatter.write_char('|')?;
                 }
@@ -894,6 +1751,11 @@ where
             }
             TagEnd::List(_) => {
                 state.list_stack.pop();
+                let marker = state.list_markers.pop();
+                state.list_number_widths.pop();
+                if state.next_is_list {
+                    state.preceding_list_marker = marker;
+                }
                 if state.list_stack.is_empty() {
                     state.set_minimum_newlines_before_start(options.newlines_after_list);
                 }
@@ -918,7 +1780,7 @@ where
             TagEnd::DefinitionListTitle => formatter.write_char('\n'),
             TagEnd::DefinitionListDefinition => {
                 state.padding.pop();
-                write_padded_newline(formatter, &state)
+                write_padded_newline(formatter, state)
             }
             TagEnd::Superscript => formatter.write_str(if options.use_html_for_super_sub_script {
                 "</sup>"
@@ -931,8 +1793,29 @@ where
                 "~"
             }),
         },
-        HardBreak => formatter.write_str("  ").and(write_padded_newline(formatter, &state)),
-        SoftBreak => write_padded_newline(formatter, &state),
+        // Only the lines of setext headings can be broken, where the break isn't on the last one.
+        HardBreak if state.in_table_cell || (state.current_heading.is_some() && state.setext_heading.is_none()) => {
+            formatter.write_str("<br>")
+        }
+        HardBreak => {
+            formatter.write_str(match options.hard_break_style {
+                HardBreakStyle::TrailingSpaces => "  ",
+                HardBreakStyle::Backslash => "\\",
+                HardBreakStyle::Html => "<br>",
+            })?;
+            write_padded_newline(formatter, state)
+        }
+        SoftBreak if wraps_text(formatter, state, options) => {
+            if options.wrap_mode == WrapMode::Unwrap {
+                formatter.write_char(' ')
+            } else {
+                state.held_back_spaces = state.held_back_spaces.max(1);
+                Ok(())
+            }
+        }
+        // ATX headings can't span multiple lines.
+        SoftBreak if state.current_heading.is_some() && state.setext_heading.is_none() => formatter.write_char(' '),
+        SoftBreak => write_padded_newline(formatter, state),
         Text(text) => {
             let mut text = &text[..];
             if let Some(shortcut_text) = state.current_shortcut_text.as_mut() {
@@ -952,12 +1835,33 @@ where
                 }
             }
             state.last_was_text_without_trailing_newline = !text.ends_with('\n');
-            let escaped_text = escape_special_characters(text, state, options);
-            print_text_without_trailing_newline(&escaped_text, formatter, &state)
+            let preceding = formatter.line().and_then(|line| line.chars().last());
+            let mut escaped_text = escape_special_characters(text, preceding, state, options);
+            // Nothing starts a block within table cells and ATX headings.
+            if !state.is_in_code_block()
+                && !state.in_metadata_block
+                && !state.unescaped_in_source
+                && !state.in_table_cell
+                && (state.current_heading.is_none() || state.setext_heading.is_some())
+            {
+                let at_line_start = formatter.line() == Some("");
+                escaped_text = escape_block_markers(
+                    escaped_text,
+                    at_line_start,
+                    state.first_line_of_block,
+                    state.next_is_inline,
+                    options,
+                );
+            }
+            if wraps_text(formatter, state, options) {
+                print_wrapped_text(&escaped_text, formatter, state, options)
+            } else {
+                print_text_without_trailing_newline(&escaped_text, formatter, state)
+            }
         }
         InlineHtml(text) => {
             consume_newlines(formatter, state)?;
-            print_text_without_trailing_newline(text, formatter, &state)
+            print_text_without_trailing_newline(text, formatter, state)
         }
         Html(text) => {
             let mut lines = text.split('\n');
@@ -965,7 +1869,7 @@ where
                 formatter.write_str(line)?;
             }
             for line in lines {
-                write_padded_newline(formatter, &state)?;
+                write_padded_newline(formatter, state)?;
                 formatter.write_str(line)?;
             }
             Ok(())
@@ -995,17 +1899,85 @@ impl State<'_> {
             return Ok(self);
         }
 
-        formatter.write_str("\n")?;
-        let mut written_shortcuts = HashSet::new();
-        for shortcut in self.shortcuts.drain(..) {
-            if written_shortcuts.contains(&shortcut) {
-                continue;
+        formatter.write_str("\n\n")?;
+        self.image_shortcuts = 0;
+        write_shortcuts(self.shortcuts.drain(..), &mut BTreeSet::new(), &mut formatter)?;
+        Ok(self)
+    }
+
+    /// Returns `true` if not within any container block like a list or a block quote.
+    fn is_at_top_level(&self) -> bool {
+        self.padding.is_empty() && self.list_stack.is_empty()
+    }
+
+    /// Write the reference definitions collected so far as a block of their own, if there are any.
+    fn write_shortcuts_block<F>(&mut self, formatter: &mut F) -> fmt::Result
+    where
+        F: fmt::Write,
+    {
+        if self.shortcuts.is_empty() {
+            return Ok(());
+        }
+        self.set_minimum_newlines_before_start(2);
+        consume_newlines(formatter, self)?;
+        self.image_shortcuts = 0;
+        write_shortcuts(self.shortcuts.drain(..), &mut self.written_shortcuts, formatter)?;
+        self.set_minimum_newlines_before_start(2);
+        Ok(())
+    }
+
+    /// The label, URI and title of each reference definition seen so far.
+    fn definitions(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.shortcuts
+            .iter()
+            .chain(&self.written_shortcuts)
+            .map(|(label, uri, title)| (label.as_str(), uri.as_str(), title.as_str()))
+    }
+
+    /// The label of the reference definition for `uri` and `title`, which is the one of an existing
+    /// definition, or the lowest number no definition seen so far uses.
+    fn reference_label(&self, uri: &str, title: &str) -> String {
+        if let Some((label, _, _)) = self.definitions().find(|(_, u, t)| *u == uri && *t == title) {
+            return label.to_owned();
+        }
+        (1..)
+            .map(|number: usize| number.to_string())
+            .find(|number| !self.definitions().any(|(label, _, _)| labels_match(label, number)))
+            .expect("there are fewer definitions than numbers")
+    }
+
+    /// Returns `true` if a definition seen so far uses `label` for another URI or title than `uri` and `title`,
+    /// which happens if it was generated for an inline link before a definition with that label was seen.
+    fn label_is_taken(&self, label: &str, uri: &str, title: &str) -> bool {
+        self.definitions()
+            .any(|(l, u, t)| labels_match(l, label) && (u != uri || t != title))
+    }
+
+    /// Remember the definition of a reference link or image at the position
+    /// [`Options::reference_definition_order`] asks for.
+    fn add_shortcut(&mut self, label: String, uri: &str, title: &str, is_image: bool, options: &Options<'_>) {
+        if self
+            .written_shortcuts
+            .contains(&(label.clone(), uri.to_owned(), title.to_owned()))
+        {
+            return;
+        }
+        let position = match options.reference_definition_order {
+            ReferenceDefinitionOrder::FirstUse => self.shortcuts.len(),
+            ReferenceDefinitionOrder::Alphabetical => {
+                let key = label.to_lowercase();
+                self.shortcuts
+                    .iter()
+                    .position(|(known, _, _)| known.to_lowercase() > key)
+                    .unwrap_or(self.shortcuts.len())
             }
-            write!(formatter, "\n[{}", shortcut.0)?;
-            close_link(&shortcut.1, &shortcut.2, &mut formatter, LinkType::Shortcut)?;
-            written_shortcuts.insert(shortcut);
+            ReferenceDefinitionOrder::LinksBeforeImages if is_image => self.shortcuts.len(),
+            ReferenceDefinitionOrder::LinksBeforeImages => self.shortcuts.len().saturating_sub(self.image_shortcuts),
+        };
+        if is_image {
+            self.image_shortcuts += 1;
         }
-        Ok(self)
+        self.shortcuts.insert(position, (label, uri.into(), title.into()));
     }
 
     /// Returns `true` if currently serializing content inside a code block.
@@ -1022,6 +1994,32 @@ impl State<'_> {
     }
 }
 
+/// Write `shortcuts` as reference definitions on separate lines, skipping the ones in `written_shortcuts`
+/// which is extended with all written ones.
+fn write_shortcuts<F>(
+    shortcuts: impl Iterator<Item = (String, String, String)>,
+    written_shortcuts: &mut BTreeSet<(String, String, String)>,
+    formatter: &mut F,
+) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    let mut is_first_line = true;
+    for shortcut in shortcuts {
+        if written_shortcuts.contains(&shortcut) {
+            continue;
+        }
+        if !is_first_line {
+            formatter.write_char('\n')?;
+        }
+        is_first_line = false;
+        write!(formatter, "[{}", shortcut.0)?;
+        close_link(&shortcut.1, &shortcut.2, &mut *formatter, LinkType::Shortcut)?;
+        written_shortcuts.insert(shortcut);
+    }
+    Ok(())
+}
+
 /// Return the `<seen amount of consecutive fenced code-block tokens> + 1` that occur *within* a
 /// fenced code-block `events`.
 ///
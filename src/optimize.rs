@@ -0,0 +1,147 @@
+use pulldown_cmark::Parser;
+
+use super::{cmark_with_options, fmt, Borrow, Error, EscapePolicy, Event, Options, Range, State};
+
+/// As [`cmark_with_options()`](super::cmark_with_options), but the output is parsed again with
+/// [`Options::parser_options`] to make its backslash escapes as few as possible while keeping them correct.
+///
+/// Escapes are added where the output doesn't parse into `events`, like for characters the
+/// escaping heuristics missed, and each escape is removed if the output still parses the same without it.
+/// If escapes can't make the output parse into `events` at all, like for emphasis nested in emphasis,
+/// the output is written with [`EscapePolicy::Conservative`] instead if [`Options::escape_policy`] escapes less,
+/// and its escapes are only removed where that doesn't change what it parses into.
+///
+/// As the output is parsed once for each escape it contains, this is a lot slower than
+/// [`cmark_with_options()`](super::cmark_with_options), and buffers all `events` along with the output.
+pub fn cmark_with_optimized_escapes<'a, I, E, F>(
+    events: I,
+    mut formatter: F,
+    options: Options<'_>,
+) -> Result<State<'a>, Error>
+where
+    I: Iterator<Item = E>,
+    E: Borrow<Event<'a>>,
+    F: fmt::Write,
+{
+    let events: Vec<Event<'a>> = events.map(|event| event.borrow().clone()).collect();
+    let parser_options = options.parser_options;
+    let fallback_options = (options.escape_policy < EscapePolicy::Conservative).then(|| Options {
+        escape_policy: EscapePolicy::Conservative,
+        ..options.clone()
+    });
+    let mut output = String::new();
+    let mut state = cmark_with_options(events.iter(), &mut output, options)?;
+
+    let expected: Vec<_> = merge_text(events.iter().map(|event| (event.clone(), 0..0)))
+        .into_iter()
+        .map(|(event, _)| event)
+        .collect();
+    if !add_missing_escapes(&mut output, &expected, parser_options) {
+        if let Some(fallback_options) = fallback_options {
+            output.clear();
+            state = cmark_with_options(events.iter(), &mut output, fallback_options)?;
+        }
+    }
+    remove_needless_escapes(&mut output, parser_options);
+
+    formatter.write_str(&output)?;
+    Ok(state)
+}
+
+/// Parse `markdown` into events with their ranges, with consecutive text merged, as the same text can be
+/// split differently depending on the escapes it contains.
+fn parse(markdown: &str, parser_options: pulldown_cmark::Options) -> Vec<(Event<'_>, Range<usize>)> {
+    merge_text(Parser::new_ext(markdown, parser_options).into_offset_iter())
+}
+
+fn merge_text<'a>(events: impl IntoIterator<Item = (Event<'a>, Range<usize>)>) -> Vec<(Event<'a>, Range<usize>)> {
+    let mut merged: Vec<(Event<'a>, Range<usize>)> = Vec::new();
+    for (event, range) in events {
+        match (merged.last_mut(), event) {
+            (Some((Event::Text(text), last_range)), Event::Text(more)) => {
+                *text = format!("{text}{more}").into();
+                last_range.end = range.end;
+            }
+            (_, event) => merged.push((event, range)),
+        }
+    }
+    merged
+}
+
+/// Returns the index of the first event of `parsed` that differs from `expected`, if any.
+fn first_difference(parsed: &[(Event<'_>, Range<usize>)], expected: &[Event<'_>]) -> Option<usize> {
+    let common = parsed
+        .iter()
+        .zip(expected)
+        .take_while(|((parsed, _), expected)| parsed == *expected)
+        .count();
+    (common < parsed.len().max(expected.len())).then_some(common)
+}
+
+/// Escape characters of `output` until it parses into `expected`, one at a time and only if that makes
+/// more of it parse as expected, trying the characters on the line where the parsed events start to differ
+/// up to the end of the next event.
+///
+/// Returns `false` and leaves `output` as it is if it can't be made to parse into `expected` that way.
+fn add_missing_escapes(output: &mut String, expected: &[Event<'_>], parser_options: pulldown_cmark::Options) -> bool {
+    let mut escaped = output.clone();
+    loop {
+        let parsed = parse(&escaped, parser_options);
+        let Some(difference) = first_difference(&parsed, expected) else {
+            *output = escaped;
+            return true;
+        };
+        let end = parsed
+            .get(difference + 1)
+            .or_else(|| parsed.get(difference))
+            .map_or(escaped.len(), |(_, range)| range.end);
+        let start = parsed
+            .get(difference)
+            .map_or(escaped.len(), |(_, range)| range.start.min(end));
+        let start = escaped[..start].rfind('\n').map_or(0, |newline| newline + 1);
+
+        let improvement = escaped[start..end]
+            .char_indices()
+            .map(|(offset, c)| (start + offset, c))
+            .filter(|(offset, c)| c.is_ascii_punctuation() && !is_escaped(&escaped, *offset))
+            .map(|(offset, _)| {
+                let mut candidate = escaped.clone();
+                candidate.insert(offset, '\\');
+                candidate
+            })
+            .find(|candidate| {
+                first_difference(&parse(candidate, parser_options), expected).map_or(true, |d| d > difference)
+            });
+        match improvement {
+            Some(improved) => escaped = improved,
+            None => return false,
+        }
+    }
+}
+
+/// Remove each backslash escape from `output` which doesn't change the events it parses into.
+fn remove_needless_escapes(output: &mut String, parser_options: pulldown_cmark::Options) {
+    let expected: Vec<_> = parse(output, parser_options)
+        .into_iter()
+        .map(|(event, _)| event.into_static())
+        .collect();
+    let escapes: Vec<usize> = output
+        .char_indices()
+        .zip(output.chars().skip(1))
+        .filter(|((offset, c), next)| *c == '\\' && next.is_ascii_punctuation() && !is_escaped(output, *offset))
+        .map(|((offset, _), _)| offset)
+        .collect();
+    // Removing from the back keeps the offsets of the remaining escapes valid.
+    for offset in escapes.into_iter().rev() {
+        let mut candidate = output.clone();
+        candidate.remove(offset);
+        if first_difference(&parse(&candidate, parser_options), &expected).is_none() {
+            *output = candidate;
+        }
+    }
+}
+
+/// Returns `true` if the character at `offset` of `s` follows a backslash which isn't escaped itself.
+fn is_escaped(s: &str, offset: usize) -> bool {
+    s[..offset].bytes().rev().take_while(|b| *b == b'\\').count() % 2 == 1
+}
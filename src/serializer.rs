@@ -0,0 +1,368 @@
+use super::{
+    cmark_resume_one_event, cmark_resume_one_event_with_source_range, fmt, is_inline, Borrow, Error, Event,
+    EventValidation, ListNumberPadding, Options, OrderedListNumbering, Range, State, Tag, TagEnd, Validator,
+};
+
+/// A push-based serializer which owns the writer, the [`State`] and the [`Options`] of a
+/// serialization, so that events can be handed over one at a time.
+///
+/// As the [`Options`] can't be changed once the serialization started, resuming with options
+/// that differ from the ones the [`State`] was produced with is impossible.
+///
+/// ```rust
+/// use pulldown_cmark::Parser;
+/// use pulldown_cmark_to_cmark::{Options, Serializer};
+///
+/// let input_markdown = "# Hello\n\n[World][w]!\n\n[w]: https://example.com";
+/// let mut serializer = Serializer::new(String::new(), Options::default());
+/// for event in Parser::new(input_markdown) {
+///     serializer.push(event).unwrap();
+/// }
+/// let (output_markdown, _state) = serializer.finish().unwrap();
+/// assert_eq!(output_markdown, input_markdown);
+/// ```
+///
+/// Note that the last pushed event is only written once the next event is pushed, or when the
+/// serializer is [finished](Serializer::finish()) or [suspended](Serializer::suspend()),
+/// as its serialization depends on what follows it.
+///
+/// Consecutive [`Event::Text`] are written as one, so the output doesn't depend on how text is split
+/// into events. This isn't done with a [`source`](Serializer::with_source()), where text is split
+/// at the escapes to preserve.
+#[derive(Debug)]
+pub struct Serializer<'a, 'o, W> {
+    writer: W,
+    state: State<'a>,
+    options: Options<'o>,
+    source: Option<&'a str>,
+    /// The last pushed event along with its range and index.
+    pending: Option<(Event<'a>, Option<Range<usize>>, usize)>,
+    /// The text of the pending `Text` event and those following it, once there is more than one.
+    pending_text: String,
+    /// The amount of events pushed so far.
+    event_count: usize,
+    /// Checks the pushed events with [`EventValidation::Strict`].
+    validator: Validator,
+    /// The events of an ordered list along with their ranges and indices, kept until the list ends to learn
+    /// the widest number of its items if [`Options::ordered_list_number_padding`] needs it.
+    list_buffer: Vec<(Event<'a>, Option<Range<usize>>, usize)>,
+    /// The amount of lists in `list_buffer` which didn't end yet.
+    open_buffered_lists: usize,
+}
+
+impl<'a, 'o, W> Serializer<'a, 'o, W>
+where
+    W: fmt::Write,
+{
+    /// Create a new serializer writing into `writer`, configured with `options`.
+    pub fn new(writer: W, options: Options<'o>) -> Self {
+        Self::resume(writer, State::default(), options)
+    }
+
+    /// Create a serializer that continues where `state` left off, writing into `writer`.
+    ///
+    /// `state` is typically obtained from [`Serializer::suspend()`], and `options` should be the
+    /// ones it was produced with.
+    pub fn resume(writer: W, state: State<'a>, options: Options<'o>) -> Self {
+        Serializer {
+            writer,
+            validator: Validator::new(state.open_containers.clone()),
+            state,
+            list_buffer: Vec::new(),
+            open_buffered_lists: 0,
+            options,
+            source: None,
+            pending: None,
+            pending_text: String::new(),
+            event_count: 0,
+        }
+    }
+
+    /// Preserve the escape characters found in `source` for all events pushed with a range, as
+    /// done by [`cmark_resume_with_source_range_and_options()`](super::cmark_resume_with_source_range_and_options).
+    ///
+    /// Without a `source`, ranges passed to [`Serializer::push_with_range()`] are ignored.
+    pub fn with_source(mut self, source: &'a str) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Return the current state of the serialization, for introspection.
+    ///
+    /// Note that it doesn't reflect the last pushed event yet.
+    pub fn state(&self) -> &State<'a> {
+        &self.state
+    }
+
+    /// Return the options this serializer was configured with.
+    pub fn options(&self) -> &Options<'o> {
+        &self.options
+    }
+
+    /// Serialize `event`.
+    pub fn push(&mut self, event: Event<'a>) -> Result<(), Error> {
+        self.push_event(event, None)
+    }
+
+    /// Serialize `event` which was parsed from `range` of the source passed to
+    /// [`Serializer::with_source()`].
+    pub fn push_with_range(&mut self, event: Event<'a>, range: Range<usize>) -> Result<(), Error> {
+        self.push_event(event, Some(range))
+    }
+
+    /// Serialize all `events`, for example as returned by the [`Parser`][pulldown_cmark::Parser].
+    pub fn extend<I, E>(&mut self, events: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = E>,
+        E: Borrow<Event<'a>>,
+    {
+        self.extend_with_ranges(events.into_iter().map(|event| (event, None)))
+    }
+
+    /// Serialize all events along with their optional source range, for example as returned by
+    /// [`pulldown_cmark::OffsetIter`].
+    ///
+    /// With [`EventValidation::Strict`], all events are buffered and checked before the first one is written.
+    pub fn extend_with_ranges<I, E>(&mut self, event_and_ranges: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (E, Option<Range<usize>>)>,
+        E: Borrow<Event<'a>>,
+    {
+        if self.options.event_validation == EventValidation::Strict {
+            let event_and_ranges: Vec<_> = event_and_ranges.into_iter().collect();
+            let mut validator = self.validator.clone();
+            for (offset, (event, _)) in event_and_ranges.iter().enumerate() {
+                validator.check(event.borrow(), self.event_count + offset)?;
+            }
+            return self.push_all(event_and_ranges);
+        }
+        self.push_all(event_and_ranges)
+    }
+
+    /// Serialize all events, without cloning those which can be written as soon as the one following them is known.
+    fn push_all<I, E>(&mut self, event_and_ranges: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (E, Option<Range<usize>>)>,
+        E: Borrow<Event<'a>>,
+    {
+        let mut event_and_ranges = event_and_ranges.into_iter().peekable();
+        while let Some((event, range)) = event_and_ranges.next() {
+            let event = event.borrow();
+            match event_and_ranges.peek() {
+                Some((next, _)) if self.can_write_right_away(event, next.borrow()) => {
+                    let index = self.event_count;
+                    self.event_count += 1;
+                    if self.options.event_validation == EventValidation::Strict {
+                        self.validator.check(event, index)?;
+                    }
+                    self.flush_pending(Some(event))?;
+                    self.serialize(event, range, Some(next.borrow()), index)?;
+                }
+                _ => self.push_event(event.clone(), range)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if `event`, followed by `next`, can be written without being kept as the pending event
+    /// or in the list buffer, or being repaired.
+    fn can_write_right_away(&self, event: &Event<'a>, next: &Event<'a>) -> bool {
+        let merges_text = self.source.is_none()
+            && matches!(event, Event::Text(_))
+            && matches!(
+                (&self.pending, next),
+                (Some((Event::Text(_), ..)), _) | (_, Event::Text(_))
+            );
+        self.options.event_validation != EventValidation::Repair
+            && self.open_buffered_lists == 0
+            && !(self.needs_list_lengths() && matches!(event, Event::Start(Tag::List(Some(_)))))
+            && !merges_text
+    }
+
+    /// Write all pending output and return the writer along with the [`State`], which can be
+    /// passed to [`Serializer::resume()`] to continue the serialization later.
+    ///
+    /// Reference definitions are not written, which is what [`Serializer::finish()`] is for.
+    pub fn suspend(mut self) -> Result<(W, State<'a>), Error> {
+        self.flush_list_buffer()?;
+        self.flush_pending(None)?;
+        Ok((self.writer, self.state))
+    }
+
+    /// Write all pending output and [finalize](State::finalize()) the serialization,
+    /// returning the writer and the final [`State`].
+    ///
+    /// *Errors* with [`Error::UnclosedContainers`] before finalizing if containers were left open,
+    /// with [`EventValidation::Strict`].
+    ///
+    /// With [`EventValidation::Repair`], containers left open are closed instead.
+    pub fn finish(mut self) -> Result<(W, State<'a>), Error> {
+        if self.options.event_validation == EventValidation::Repair {
+            for closing in self.validator.close_all(self.event_count, &mut self.state) {
+                self.push_checked_event(closing, None, self.event_count)?;
+            }
+        }
+        let strict = self.options.event_validation == EventValidation::Strict;
+        let (mut writer, state) = self.suspend()?;
+        if strict && !state.open_containers.is_empty() {
+            return Err(Error::UnclosedContainers(state.open_containers));
+        }
+        let state = state.finalize(&mut writer)?;
+        Ok((writer, state))
+    }
+
+    fn push_event(&mut self, event: Event<'a>, range: Option<Range<usize>>) -> Result<(), Error> {
+        let index = self.event_count;
+        self.event_count += 1;
+        match self.options.event_validation {
+            EventValidation::Unchecked => self.push_checked_event(event, range, index),
+            EventValidation::Strict => {
+                self.validator.check(&event, index)?;
+                self.push_checked_event(event, range, index)
+            }
+            EventValidation::Repair => {
+                let (inserted, event) = self.validator.repair(event, index, &mut self.state);
+                for inserted in inserted {
+                    self.push_checked_event(inserted, None, index)?;
+                }
+                match event {
+                    Some(event) => self.push_checked_event(event, range, index),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn push_checked_event(&mut self, event: Event<'a>, range: Option<Range<usize>>, index: usize) -> Result<(), Error> {
+        if self.needs_list_lengths()
+            && (self.open_buffered_lists > 0 || matches!(event, Event::Start(Tag::List(Some(_)))))
+        {
+            match event {
+                Event::Start(Tag::List(_)) => self.open_buffered_lists += 1,
+                Event::End(TagEnd::List(_)) => self.open_buffered_lists -= 1,
+                _ => {}
+            }
+            self.list_buffer.push((event, range, index));
+            if self.open_buffered_lists == 0 {
+                self.flush_list_buffer()?;
+            }
+            return Ok(());
+        }
+        self.push_pending(event, range, index)
+    }
+
+    /// Returns `true` if ordered lists are buffered until they end, as the width of their numbers depends on
+    /// their amount of items.
+    fn needs_list_lengths(&self) -> bool {
+        self.options.ordered_list_number_padding != ListNumberPadding::Unpadded
+            && self.options.effective_ordered_list_numbering() != OrderedListNumbering::Same
+    }
+
+    /// Write the buffered events of ordered lists, after learning the amount of items of each of them.
+    fn flush_list_buffer(&mut self) -> Result<(), Error> {
+        let buffered = std::mem::take(&mut self.list_buffer);
+        self.open_buffered_lists = 0;
+        self.state
+            .ordered_list_lengths
+            .extend(ordered_list_lengths(buffered.iter().map(|(event, ..)| event)));
+        for (event, range, index) in buffered {
+            self.push_pending(event, range, index)?;
+        }
+        Ok(())
+    }
+
+    fn push_pending(&mut self, event: Event<'a>, range: Option<Range<usize>>, index: usize) -> Result<(), Error> {
+        if let (Some((Event::Text(pending), ..)), Event::Text(text), None) = (&self.pending, &event, self.source) {
+            if self.pending_text.is_empty() {
+                self.pending_text.push_str(pending);
+            }
+            self.pending_text.push_str(text);
+            return Ok(());
+        }
+        self.flush_pending(Some(&event))?;
+        self.pending = Some((event, range, index));
+        Ok(())
+    }
+
+    fn flush_pending(&mut self, next: Option<&Event<'a>>) -> Result<(), Error> {
+        let Some((mut pending, pending_range, index)) = self.pending.take() else {
+            return Ok(());
+        };
+        if !self.pending_text.is_empty() {
+            pending = Event::Text(std::mem::take(&mut self.pending_text).into());
+        }
+        self.serialize(&pending, pending_range, next, index)
+    }
+
+    /// Serialize `event`, the one at `index`, knowing the `next` one.
+    fn serialize(
+        &mut self,
+        event: &Event<'a>,
+        range: Option<Range<usize>>,
+        next: Option<&Event<'a>>,
+        index: usize,
+    ) -> Result<(), Error> {
+        self.state.next_is_link_like = matches!(
+            next,
+            Some(
+                Event::Start(Tag::Link { .. } | Tag::Image { .. } | Tag::FootnoteDefinition(..))
+                    | Event::FootnoteReference(..)
+            )
+        );
+        self.state.next_is_list = matches!(next, Some(Event::Start(Tag::List(_))));
+        self.state.next_is_indented_code_block = matches!(
+            next,
+            Some(Event::Start(Tag::CodeBlock(pulldown_cmark::CodeBlockKind::Indented)))
+        );
+        self.state.next_is_inline = match next {
+            Some(Event::Start(tag)) => is_inline(&tag.to_end()),
+            Some(Event::End(tag)) => is_inline(tag),
+            Some(Event::Code(_) | Event::InlineMath(_) | Event::InlineHtml(_) | Event::FootnoteReference(_)) => true,
+            _ => false,
+        };
+        match self.source {
+            Some(source) => cmark_resume_one_event_with_source_range(
+                event,
+                range,
+                source,
+                &mut self.writer,
+                &mut self.state,
+                &self.options,
+            ),
+            None => cmark_resume_one_event(event, &mut self.writer, &mut self.state, &self.options),
+        }
+        .map_err(|mut err| {
+            if let Error::UnexpectedEvent(unexpected) = &mut err {
+                unexpected.index = index;
+            }
+            err
+        })
+    }
+}
+
+/// The amount of items of each ordered list started in `events`, in the order they start.
+fn ordered_list_lengths<'e, 'a: 'e>(events: impl Iterator<Item = &'e Event<'a>>) -> Vec<usize> {
+    let mut lengths = Vec::new();
+    // The index into `lengths` of each open list, or `None` for unordered ones.
+    let mut open_lists = Vec::new();
+    for event in events {
+        match event {
+            Event::Start(Tag::List(start)) => {
+                open_lists.push(start.map(|_| lengths.len()));
+                if start.is_some() {
+                    lengths.push(0);
+                }
+            }
+            Event::Start(Tag::Item) => {
+                if let Some(Some(list)) = open_lists.last() {
+                    lengths[*list] += 1;
+                }
+            }
+            Event::End(TagEnd::List(_)) => {
+                open_lists.pop();
+            }
+            _ => {}
+        }
+    }
+    lengths
+}
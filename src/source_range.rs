@@ -1,11 +1,14 @@
-use super::{cmark_resume_one_event, fmt, Borrow, Error, Event, Options, Range, State};
+use super::{
+    cmark_resume_one_event, fmt, smart_punctuation_source, Borrow, Error, Event, Options, Range, Serializer, State,
+};
 
 /// Serialize a stream of [pulldown-cmark-Events][Event] while preserving the escape characters in `source`.
 /// Each input [Event] is accompanied by an optional [Range] that maps it back to the `source` string.
 ///
 /// Different from [`cmark_resume_with_options`](super::cmark_resume_with_options), which always escape
 /// Markdown special characters like `#` or `[`, this function only escapes a special character if
-/// it is escaped in `source`.
+/// it is escaped in `source`. A `!` right before a link is escaped either way, as it would turn the link
+/// into an image otherwise.
 ///
 /// 1. **source**
 ///     * Markdown source from which `event_and_ranges` are created.
@@ -38,40 +41,72 @@ where
     E: Borrow<Event<'a>>,
     F: fmt::Write,
 {
-    let mut state = state.unwrap_or_default();
-    for (event, range) in event_and_ranges {
-        let update_event_end_index = !matches!(*event.borrow(), Event::Start(_));
-        let prevent_escape_leading_special_characters = match (&range, event.borrow()) {
-            // Headers and tables can have special characters that aren't at the start
-            // of the line, because headers end with `#` and tables have pipes in the middle.
-            _ if state.current_heading.is_some() || !state.table_alignments.is_empty() => false,
-            // IMPORTANT: Any changes that allow anything other than `Text`
-            // breaks the assumption below.
-            (Some(range), Event::Text(_)) => {
-                range.start <= state.last_event_end_index ||
-                // Some source characters are not captured,
-                // so check the previous character.
-                source.as_bytes().get(range.start.saturating_sub(1)) != Some(&b'\\')
-            }
-            _ => false,
-        } && !state.is_in_code_block();
-        if prevent_escape_leading_special_characters {
-            // Hack to not escape leading special characters.
-            state.code_block = Some(crate::CodeBlockKind::Fenced);
+    let mut serializer = Serializer::resume(&mut formatter, state.unwrap_or_default(), options).with_source(source);
+    serializer.extend_with_ranges(event_and_ranges)?;
+    Ok(serializer.suspend()?.1)
+}
+
+/// Serialize a single `event` as [`cmark_resume_with_source_range_and_options`] does,
+/// with `range` mapping it back to `source`.
+pub(crate) fn cmark_resume_one_event_with_source_range<'a, E, F>(
+    event: E,
+    range: Option<Range<usize>>,
+    source: &'a str,
+    formatter: &mut F,
+    state: &mut State<'a>,
+    options: &Options<'_>,
+) -> Result<(), Error>
+where
+    E: Borrow<Event<'a>>,
+    F: fmt::Write,
+{
+    let update_event_end_index = !matches!(*event.borrow(), Event::Start(_));
+    let prevent_escape_leading_special_characters = match (&range, event.borrow()) {
+        // Headers and tables can have special characters that aren't at the start
+        // of the line, because headers end with `#` and tables have pipes in the middle.
+        _ if state.current_heading.is_some() || !state.table_alignments.is_empty() => false,
+        // IMPORTANT: Any changes that allow anything other than `Text`
+        // breaks the assumption below.
+        (Some(range), Event::Text(_)) => {
+            range.start <= state.last_event_end_index ||
+            // Some source characters are not captured,
+            // so check the previous character.
+            source.as_bytes().get(range.start.saturating_sub(1)) != Some(&b'\\')
         }
-        cmark_resume_one_event(event, &mut formatter, &mut state, &options)?;
-        if prevent_escape_leading_special_characters {
-            // Assumption: this case only happens when `event` is `Text`,
-            // so `state.is_in_code_block` should not be changed to `true`.
-            // Also, `state.is_in_code_block` was `false`.
-            state.code_block = None;
+        _ => false,
+    } && !state.is_in_code_block();
+    // Smart punctuation is written as it was in `source`, like `"` for both `“` and `”`.
+    let smart_punctuation_source = match (&range, event.borrow()) {
+        (Some(range), Event::Text(text)) if options.reverse_smart_punctuation && is_smart_punctuation(text) => {
+            source.get(range.clone())
         }
-
-        if let (true, Some(range)) = (update_event_end_index, range) {
-            state.last_event_end_index = range.end;
+        _ => None,
+    };
+    let res = match smart_punctuation_source {
+        Some(source) => {
+            state.unescaped_in_source = true;
+            cmark_resume_one_event(Event::Text(source.into()), formatter, state, options)
+        }
+        None => {
+            state.unescaped_in_source = prevent_escape_leading_special_characters;
+            state.text_in_source = range.is_some();
+            cmark_resume_one_event(event, formatter, state, options)
         }
+    };
+    state.unescaped_in_source = false;
+    state.text_in_source = false;
+    res?;
+
+    if let (true, Some(range)) = (update_event_end_index, range) {
+        state.last_event_end_index = range.end;
     }
-    Ok(state)
+    Ok(())
+}
+
+/// Returns `true` if `text` is made of curly quotes, dashes or ellipses only, as produced by smart punctuation,
+/// like the two en dashes of `----`.
+fn is_smart_punctuation(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| smart_punctuation_source(c).is_some())
 }
 
 /// As [`cmark_resume_with_source_range_and_options`], but with default [`Options`].
@@ -101,14 +136,9 @@ where
     E: Borrow<Event<'a>>,
     F: fmt::Write,
 {
-    let state = cmark_resume_with_source_range_and_options(
-        event_and_ranges,
-        source,
-        &mut formatter,
-        Default::default(),
-        options,
-    )?;
-    state.finalize(formatter)
+    let mut serializer = Serializer::new(&mut formatter, options).with_source(source);
+    serializer.extend_with_ranges(event_and_ranges)?;
+    Ok(serializer.finish()?.1)
 }
 
 /// As [`cmark_with_source_range_and_options`], but with default [`Options`].
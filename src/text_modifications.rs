@@ -1,6 +1,6 @@
 use super::{
     fmt::{self, Write},
-    Cow, LinkType, Options, State,
+    Alignment, Cow, Heading, LinkCategory, LinkType, ListIndentation, Options, State, WrapMode,
 };
 
 /// Write a newline followed by the current [`State::padding`]
@@ -37,6 +37,54 @@ pub(crate) fn write_padded_newline(formatter: &mut impl fmt::Write, state: &Stat
     Ok(())
 }
 
+/// Formats as all padding in order.
+pub(crate) struct Padding<'p, 'a>(pub &'p [Cow<'a, str>]);
+
+impl fmt::Display for Padding<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        padding(f, self.0)
+    }
+}
+
+/// Write the `{ #id .class key=value }` block of `heading`, if it has any attributes.
+pub(crate) fn write_heading_attributes<F>(f: &mut F, heading: &Heading<'_>) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    let Heading {
+        id,
+        classes,
+        attributes,
+    } = heading;
+    let emit_braces = id.is_some() || !classes.is_empty() || !attributes.is_empty();
+    if emit_braces {
+        f.write_str(" {")?;
+    }
+    if let Some(id_str) = id {
+        f.write_char(' ')?;
+        f.write_char('#')?;
+        f.write_str(id_str)?;
+    }
+    for class in classes {
+        f.write_char(' ')?;
+        f.write_char('.')?;
+        f.write_str(class)?;
+    }
+    for (key, val) in attributes {
+        f.write_char(' ')?;
+        f.write_str(key)?;
+        if let Some(val) = val {
+            f.write_char('=')?;
+            f.write_str(val)?;
+        }
+    }
+    if emit_braces {
+        f.write_char(' ')?;
+        f.write_char('}')?;
+    }
+    Ok(())
+}
+
 pub(crate) fn padding<F>(f: &mut F, p: &[Cow<'_, str>]) -> fmt::Result
 where
     F: fmt::Write,
@@ -71,10 +119,294 @@ where
     Ok(())
 }
 
-pub(crate) fn list_item_padding_of(l: Option<u64>) -> Cow<'static, str> {
-    match l {
-        None => "  ".into(),
-        Some(n) => format!("{n}. ").chars().map(|_| ' ').collect::<String>().into(),
+/// A writer which keeps track of the content written to the current line, which is what
+/// [`State::current_line`] contains between events.
+///
+/// The padding written after each newline isn't part of the content, and is skipped.
+pub(crate) struct LineTracker<'f, F> {
+    inner: &'f mut F,
+    line: Option<String>,
+    padding_width: usize,
+    padding_to_skip: usize,
+    wrote_newline: bool,
+}
+
+impl<'f, F> LineTracker<'f, F>
+where
+    F: fmt::Write,
+{
+    /// Track the line of `state`, which is taken until [`LineTracker::into_line()`] is called.
+    pub(crate) fn new(inner: &'f mut F, state: &mut State<'_>) -> Self {
+        LineTracker {
+            inner,
+            line: state.current_line.take(),
+            padding_width: padding_width(&state.padding),
+            padding_to_skip: 0,
+            wrote_newline: false,
+        }
+    }
+
+    /// The content of the current line, if it is tracked.
+    pub(crate) fn line(&self) -> Option<&str> {
+        self.line.as_deref()
+    }
+
+    /// The column the next character will be written to, including the padding.
+    pub(crate) fn column(&self) -> usize {
+        self.padding_width + self.line.as_deref().map_or(0, text_width)
+    }
+
+    /// Returns `true` if a newline was written through this instance.
+    pub(crate) fn wrote_newline(&self) -> bool {
+        self.wrote_newline
+    }
+
+    pub(crate) fn into_line(self) -> Option<String> {
+        self.line
+    }
+}
+
+impl<F> fmt::Write for LineTracker<'_, F>
+where
+    F: fmt::Write,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_str(s)?;
+        self.wrote_newline |= s.contains('\n');
+        if let Some(line) = self.line.as_mut() {
+            for c in s.chars() {
+                if c == '\n' {
+                    line.clear();
+                    self.padding_to_skip = self.padding_width;
+                } else if self.padding_to_skip > 0 {
+                    self.padding_to_skip -= 1;
+                } else {
+                    line.push(c);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn padding_width(p: &[Cow<'_, str>]) -> usize {
+    p.iter().map(|padding| text_width(padding)).sum()
+}
+
+/// The amount of columns `text` occupies when printed.
+#[cfg(not(feature = "unicode-width"))]
+pub(crate) fn text_width(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// The amount of columns `text` occupies when printed, with each grapheme cluster taking
+/// its East Asian display width.
+#[cfg(feature = "unicode-width")]
+pub(crate) fn text_width(text: &str) -> usize {
+    use unicode_segmentation::UnicodeSegmentation;
+    use unicode_width::UnicodeWidthStr;
+
+    // Terminals and editors don't render a single grapheme cluster wider than two columns,
+    // even if it's made of multiple wide characters like emoji ZWJ sequences.
+    text.graphemes(true).map(|grapheme| grapheme.width().min(2)).sum()
+}
+
+/// Print `t` like [`print_text_without_trailing_newline()`], but break lines at spaces so that
+/// they don't exceed [`Options::line_width`], or join all lines for [`WrapMode::Unwrap`].
+///
+/// Spaces at the end of `t` are held back in [`State::held_back_spaces`] to let the next inline
+/// element decide if they turn into a line break.
+pub(crate) fn print_wrapped_text<F>(
+    t: &str,
+    f: &mut LineTracker<'_, F>,
+    state: &mut State<'_>,
+    options: &Options<'_>,
+) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    if options.wrap_mode == WrapMode::Unwrap {
+        return f.write_str(&t.replace('\n', " "));
+    }
+    for (wid, word) in t.split([' ', '\n']).enumerate() {
+        if wid > 0 {
+            state.held_back_spaces += 1;
+        }
+        if !word.is_empty() {
+            write_held_back_spaces(f, state, options, text_width(word), starts_like_block(word))?;
+            f.write_str(word)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the [`State::held_back_spaces`] in front of an inline element of `width` columns,
+/// or replace them with a line break if the element would exceed [`Options::line_width`].
+pub(crate) fn write_held_back_spaces<F>(
+    f: &mut LineTracker<'_, F>,
+    state: &mut State<'_>,
+    options: &Options<'_>,
+    width: usize,
+    starts_like_block: bool,
+) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    let spaces = std::mem::take(&mut state.held_back_spaces);
+    if spaces == 0 {
+        return Ok(());
+    }
+    let line_has_content = f.line().is_some_and(|line| !line.is_empty());
+    if line_has_content && !starts_like_block && f.column() + spaces + width > options.line_width {
+        write_padded_newline(f, state)
+    } else {
+        write!(f, "{}", Repeated(' ', spaces))
+    }
+}
+
+/// Returns `true` if `word` would be parsed as the marker of a block if it started a line,
+/// like `#`, `>`, `-` or `1.`.
+pub(crate) fn starts_like_block(word: &str) -> bool {
+    let digits = word.bytes().take_while(u8::is_ascii_digit).count();
+    let after_digits = &word.as_bytes()[digits..];
+    match word.as_bytes() {
+        [b'#', ..] | [b'>', ..] | [b'<', ..] | [b'|', ..] | [b':'] => true,
+        [marker @ (b'-' | b'+' | b'*' | b'=' | b'_'), rest @ ..] if rest.iter().all(|b| b == marker) => true,
+        [b'`', b'`', b'`', ..] | [b'~', b'~', b'~', ..] => true,
+        _ => (1..=9).contains(&digits) && matches!(after_digits, [b'.' | b')']),
+    }
+}
+
+/// Returns `true` if text written to `f` should be wrapped according to `options`.
+pub(crate) fn wraps_text<F>(f: &LineTracker<'_, F>, state: &State<'_>, options: &Options<'_>) -> bool
+where
+    F: fmt::Write,
+{
+    options.wrap_mode != WrapMode::Preserve
+        && f.line().is_some()
+        && state.current_heading.is_none()
+        && !state.in_table_cell
+        && !matches!(state.link_stack.last(), Some(LinkCategory::AngleBracketed))
+}
+
+/// Write the delimiter row cell of a table column with `alignment`, made of `length` characters.
+pub(crate) fn write_table_delimiter<F>(f: &mut F, alignment: Alignment, length: usize) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    let last_minus_one = length.saturating_sub(1);
+    for c in 0..length {
+        f.write_char(
+            if (c == 0 && (alignment == Alignment::Center || alignment == Alignment::Left))
+                || (c == last_minus_one && (alignment == Alignment::Center || alignment == Alignment::Right))
+            {
+                ':'
+            } else {
+                '-'
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Write all [`State::table_rows`] with each column padded to its widest cell,
+/// the first row being the header.
+pub(crate) fn write_aligned_table<F>(f: &mut F, state: &State<'_>) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    // The delimiter row looks best with at least three characters, like `---` or `:-:`.
+    const MIN_WIDTH: usize = 3;
+    let column_count = state
+        .table_rows
+        .iter()
+        .map(Vec::len)
+        .chain(Some(state.table_alignments.len()))
+        .max()
+        .unwrap_or_default();
+    let widths: Vec<_> = (0..column_count)
+        .map(|column| {
+            state
+                .table_rows
+                .iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| text_width(cell))
+                .fold(MIN_WIDTH, usize::max)
+        })
+        .collect();
+    let alignment_of = |column: usize| state.table_alignments.get(column).copied().unwrap_or(Alignment::None);
+
+    for (rid, row) in state.table_rows.iter().enumerate() {
+        if rid > 0 {
+            write_padded_newline(f, state)?;
+        }
+        f.write_char('|')?;
+        for (column, width) in widths.iter().enumerate() {
+            let cell = row.get(column).map(String::as_str).unwrap_or_default();
+            let fill = width - text_width(cell);
+            let (before, after) = match alignment_of(column) {
+                Alignment::None | Alignment::Left => (0, fill),
+                Alignment::Right => (fill, 0),
+                Alignment::Center => (fill / 2, fill - fill / 2),
+            };
+            write!(f, " {}{cell}{} |", Repeated(' ', before), Repeated(' ', after))?;
+        }
+        if rid == 0 {
+            write_padded_newline(f, state)?;
+            f.write_char('|')?;
+            for (column, width) in widths.iter().enumerate() {
+                f.write_char(' ')?;
+                write_table_delimiter(f, alignment_of(column), *width)?;
+                f.write_str(" |")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The first line of a list item up to its content, which starts with `marker` like `*` or `1.`,
+/// along with the padding of the lines continuing it.
+pub(crate) fn list_item_marker_and_padding(
+    marker: &str,
+    state: &State<'_>,
+    options: &Options<'_>,
+) -> (String, Cow<'static, str>) {
+    let marker_width = marker.chars().count();
+    // Content starting on the line after the marker is expected right after the marker and a space.
+    let indentation = if state.next_is_indented_code_block {
+        ListIndentation::MarkerWidth
+    } else {
+        options.list_indentation
+    };
+    match indentation {
+        ListIndentation::MarkerWidth => (format!("{marker} "), " ".repeat(marker_width + 1).into()),
+        ListIndentation::Columns(columns) => {
+            let width = columns.clamp(marker_width + 1, marker_width + 4);
+            (format!("{marker:<width$}"), " ".repeat(width).into())
+        }
+        ListIndentation::Tab => {
+            // The tab after the marker moves the content to the next tab stop, which the lines continuing the
+            // item have to reach with tabs as well, from the column their padding ends at.
+            let column = state
+                .padding
+                .iter()
+                .flat_map(|padding| padding.chars())
+                .fold(0, next_column);
+            let content_column = next_column(column + marker_width, '\t');
+            let tabs = content_column / TAB_WIDTH - column / TAB_WIDTH;
+            (format!("{marker}\t"), "\t".repeat(tabs).into())
+        }
+    }
+}
+
+/// The width of a tab, as far as Markdown is concerned.
+const TAB_WIDTH: usize = 4;
+
+/// The column after writing `c` at `column`.
+fn next_column(column: usize, c: char) -> usize {
+    match c {
+        '\t' => (column / TAB_WIDTH + 1) * TAB_WIDTH,
+        _ => column + 1,
     }
 }
 
@@ -82,7 +414,8 @@ pub(crate) fn close_link<F>(uri: &str, title: &str, f: &mut F, link_type: LinkTy
 where
     F: fmt::Write,
 {
-    let needs_brackets = {
+    // An empty destination has to be written as `<>` in reference definitions.
+    let needs_brackets = (uri.is_empty() && link_type == LinkType::Shortcut) || {
         let mut depth = 0;
         for b in uri.bytes() {
             match b {
@@ -138,31 +471,6 @@ impl fmt::Display for EscapeLinkTitle<'_> {
     }
 }
 
-pub(crate) fn escape_special_characters<'a>(t: &'a str, state: &State<'a>, options: &Options<'a>) -> Cow<'a, str> {
-    if state.is_in_code_block() || t.is_empty() {
-        return Cow::Borrowed(t);
-    }
-
-    let first = t.chars().next().expect("at least one char");
-    let first_special = options.special_characters().contains(first);
-    let ends_with_special =
-        (state.next_is_link_like && t.ends_with("!")) || (state.current_heading.is_some() && t.ends_with("#"));
-    let table_contains_pipe = !state.table_alignments.is_empty() && t.contains("|");
-    if first_special || ends_with_special || table_contains_pipe {
-        let mut s = String::with_capacity(t.len() + 1);
-        for (i, c) in t.char_indices() {
-            if (i == 0 && first_special) || (i == t.len() - 1 && ends_with_special) || (c == '|' && table_contains_pipe)
-            {
-                s.push('\\');
-            }
-            s.push(c);
-        }
-        Cow::Owned(s)
-    } else {
-        Cow::Borrowed(t)
-    }
-}
-
 pub(crate) fn max_consecutive_chars(text: &str, search: char) -> usize {
     let mut in_search_chars = false;
     let mut max_count = 0;
@@ -218,3 +526,25 @@ impl<T: fmt::Display> fmt::Display for Repeated<T> {
         Ok(())
     }
 }
+
+/// The token of thematic breaks configured in `options`, or `-` if it can't make up a thematic break.
+pub(crate) fn rule_token(options: &Options<'_>) -> char {
+    match options.rule_token {
+        token @ ('-' | '*' | '_') => token,
+        _ => '-',
+    }
+}
+
+/// Write a thematic break as configured in `options`.
+pub(crate) fn write_rule<F>(f: &mut F, options: &Options<'_>) -> fmt::Result
+where
+    F: fmt::Write,
+{
+    for index in 0..options.rule_token_count.max(3) {
+        if index > 0 {
+            write!(f, "{}", Repeated(' ', options.rule_token_spacing))?;
+        }
+        f.write_char(rule_token(options))?;
+    }
+    Ok(())
+}
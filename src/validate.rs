@@ -0,0 +1,317 @@
+use super::{is_inline, Borrow, Error, Event, Repair, RepairKind, State, Tag, TagEnd, UnexpectedEvent};
+
+/// Check that `events` can be produced by parsing Markdown, as done with [`EventValidation::Strict`](super::EventValidation::Strict).
+///
+/// That is, each `End` closes the innermost open container, each event appears in a container that can hold it,
+/// like text in a block holding inline content or a table cell in a table row, and all containers are closed.
+///
+/// *Errors* with [`Error::UnexpectedEvent`] for the first event that can't appear where it does, or with
+/// [`Error::UnclosedContainers`] if the containers aren't all closed in the end.
+pub fn validate_events<'a, I, E>(events: I) -> Result<(), Error>
+where
+    I: IntoIterator<Item = E>,
+    E: Borrow<Event<'a>>,
+{
+    let mut validator = Validator::default();
+    for (index, event) in events.into_iter().enumerate() {
+        validator.check(event.borrow(), index)?;
+    }
+    validator.finish()
+}
+
+/// Keeps track of the open containers of an event stream to check each of its events.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Validator {
+    /// The closing tags of the open containers, from the outermost to the innermost
+    open_containers: Vec<TagEnd>,
+}
+
+impl Validator {
+    /// Create a validator for events following the ones that opened `open_containers`.
+    pub(crate) fn new(open_containers: Vec<TagEnd>) -> Self {
+        Validator { open_containers }
+    }
+
+    /// Check that `event`, the one at `index`, can appear within the open containers, and track the one it opens or closes.
+    pub(crate) fn check(&mut self, event: &Event<'_>, index: usize) -> Result<(), Error> {
+        if !self.fits(event) {
+            return Err(Error::UnexpectedEvent(Box::new(UnexpectedEvent {
+                index,
+                event: event.clone().into_static(),
+                expected: self.open_containers.last().copied(),
+                open_containers: self.open_containers.clone(),
+            })));
+        }
+        self.track(event);
+        Ok(())
+    }
+
+    /// Repair `event`, the one at `index`, as done with [`EventValidation::Repair`](super::EventValidation::Repair),
+    /// and record the repairs in `state`.
+    ///
+    /// Returns the events to write before `event`, along with `event` unless it's dropped.
+    pub(crate) fn repair<'a>(
+        &mut self,
+        event: Event<'a>,
+        index: usize,
+        state: &mut State<'_>,
+    ) -> (Vec<Event<'a>>, Option<Event<'a>>) {
+        let mut inserted = Vec::new();
+        match &event {
+            Event::Start(Tag::Link { .. }) if self.open_containers.contains(&TagEnd::Link) => {
+                self.unwrap(TagEnd::Link, index, state);
+                return (inserted, None);
+            }
+            Event::End(tag) if self.closes_unwrapped_container(tag, state) => {
+                state.unwrapped_containers.pop();
+                return (inserted, None);
+            }
+            _ => {}
+        }
+        while !self.fits(&event) && self.implicit_container_is_innermost(state) {
+            inserted.push(self.close_innermost(state));
+        }
+        if !self.fits(&event) {
+            match &event {
+                Event::End(tag) => match self.open_containers.iter().rposition(|open| open == tag) {
+                    Some(position) => self.close_down_to(position + 1, index, state, &mut inserted),
+                    None => {
+                        state.repairs.push(Repair {
+                            index,
+                            kind: RepairKind::DroppedEnd(*tag),
+                        });
+                        return (inserted, None);
+                    }
+                },
+                event => match self.placement(event) {
+                    Some((depth, wrappers)) => {
+                        self.close_down_to(depth, index, state, &mut inserted);
+                        for wrapper in wrappers {
+                            state.repairs.push(Repair {
+                                index,
+                                kind: RepairKind::Opened(wrapper.to_end()),
+                            });
+                            state.implicit_containers.push(self.open_containers.len());
+                            self.open_containers.push(wrapper.to_end());
+                            inserted.push(Event::Start(wrapper));
+                        }
+                    }
+                    None => {
+                        let Event::Start(tag) = event else {
+                            unreachable!("all events but `Start` can be placed at the top level")
+                        };
+                        self.unwrap(tag.to_end(), index, state);
+                        return (inserted, None);
+                    }
+                },
+            }
+        }
+        self.track(&event);
+        let depth = self.open_containers.len();
+        state.implicit_containers.retain(|implicit| *implicit < depth);
+        (inserted, Some(event))
+    }
+
+    /// Close all open containers, as done at the end of the events with
+    /// [`EventValidation::Repair`](super::EventValidation::Repair), recording the repairs in `state` with `index`.
+    ///
+    /// Returns the `End` events closing them.
+    pub(crate) fn close_all(&mut self, index: usize, state: &mut State<'_>) -> Vec<Event<'static>> {
+        let mut closing = Vec::new();
+        self.close_down_to(0, index, state, &mut closing);
+        state.unwrapped_containers.clear();
+        closing
+    }
+
+    /// Close the open containers until only `depth` of them are left, adding their `End` events to `closing`
+    /// and recording a repair with `index` for each that wasn't opened by a repair.
+    fn close_down_to(&mut self, depth: usize, index: usize, state: &mut State<'_>, closing: &mut Vec<Event<'_>>) {
+        while self.open_containers.len() > depth {
+            if !self.implicit_container_is_innermost(state) {
+                let open = self.open_containers[self.open_containers.len() - 1];
+                state.repairs.push(Repair {
+                    index,
+                    kind: RepairKind::Closed(open),
+                });
+            }
+            closing.push(self.close_innermost(state));
+        }
+    }
+
+    /// Drop the `Start` event of the container closed by `tag`, and the matching `End` event later on.
+    fn unwrap(&mut self, tag: TagEnd, index: usize, state: &mut State<'_>) {
+        state.repairs.push(Repair {
+            index,
+            kind: RepairKind::Unwrapped(tag),
+        });
+        state.unwrapped_containers.push((tag, self.open_containers.len()));
+    }
+
+    /// Returns `true` if an `End` event with `tag` closes the innermost container whose `Start` event was dropped,
+    /// rather than a container opened within it.
+    fn closes_unwrapped_container(&self, tag: &TagEnd, state: &State<'_>) -> bool {
+        state.unwrapped_containers.last().is_some_and(|(unwrapped, depth)| {
+            unwrapped == tag
+                && self
+                    .open_containers
+                    .get(*depth..)
+                    .map_or(true, |within| !within.contains(tag))
+        })
+    }
+
+    /// The amount of open containers to keep, and the containers to open within them, for `event` to fit,
+    /// keeping as many open containers as possible. Returns `None` if `event` can't appear within any of them.
+    fn placement(&self, event: &Event<'_>) -> Option<(usize, Vec<Tag<'static>>)> {
+        (0..=self.open_containers.len()).rev().find_map(|depth| {
+            let mut open_containers = self.open_containers[..depth].to_vec();
+            let mut wrappers = Vec::new();
+            while !fits(&open_containers, event) {
+                let wrapper = wrapper(open_containers.last(), event)?;
+                open_containers.push(wrapper.to_end());
+                wrappers.push(wrapper);
+            }
+            Some((depth, wrappers))
+        })
+    }
+
+    /// Returns `true` if the innermost open container was opened around events with
+    /// [`EventValidation::Repair`](super::EventValidation::Repair).
+    fn implicit_container_is_innermost(&self, state: &State<'_>) -> bool {
+        state.implicit_containers.last().map(|depth| depth + 1) == Some(self.open_containers.len())
+    }
+
+    /// Close the innermost open container, returning its `End` event.
+    fn close_innermost(&mut self, state: &mut State<'_>) -> Event<'static> {
+        let open = self.open_containers.pop().expect("a container is open");
+        if state.implicit_containers.last() == Some(&self.open_containers.len()) {
+            state.implicit_containers.pop();
+        }
+        Event::End(open)
+    }
+
+    /// Keep track of the container `event` opens or closes.
+    fn track(&mut self, event: &Event<'_>) {
+        match event {
+            Event::Start(tag) => self.open_containers.push(tag.to_end()),
+            Event::End(_) => {
+                self.open_containers.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// Check that all containers were closed.
+    pub(crate) fn finish(self) -> Result<(), Error> {
+        if self.open_containers.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::UnclosedContainers(self.open_containers))
+        }
+    }
+
+    /// Returns `true` if `event` can appear within the innermost open container, or closes it.
+    fn fits(&self, event: &Event<'_>) -> bool {
+        fits(&self.open_containers, event)
+    }
+}
+
+/// Returns `true` if `event` can appear within the innermost of `open_containers`, or closes it.
+fn fits(open_containers: &[TagEnd], event: &Event<'_>) -> bool {
+    let parent = open_containers.last();
+    match event {
+        Event::End(tag) => parent == Some(tag),
+        Event::Start(Tag::Item) => matches!(parent, Some(TagEnd::List(_))),
+        Event::Start(Tag::TableHead | Tag::TableRow) => parent == Some(&TagEnd::Table),
+        Event::Start(Tag::TableCell) => matches!(parent, Some(TagEnd::TableHead | TagEnd::TableRow)),
+        Event::Start(Tag::DefinitionListTitle | Tag::DefinitionListDefinition) => {
+            parent == Some(&TagEnd::DefinitionList)
+        }
+        Event::Start(Tag::MetadataBlock(_)) => parent.is_none(),
+        // Links can't contain other links, no matter how deeply nested.
+        Event::Start(Tag::Link { .. }) => holds_inline(parent) && !open_containers.contains(&TagEnd::Link),
+        Event::Start(tag) if is_inline(&tag.to_end()) => holds_inline(parent),
+        Event::Start(_) | Event::Rule => holds_blocks(parent),
+        Event::Text(_) => {
+            holds_inline(parent)
+                || matches!(
+                    parent,
+                    Some(TagEnd::CodeBlock | TagEnd::HtmlBlock | TagEnd::MetadataBlock(_))
+                )
+        }
+        Event::Html(_) => parent == Some(&TagEnd::HtmlBlock),
+        Event::TaskListMarker(_) => matches!(
+            open_containers,
+            [.., TagEnd::Item] | [.., TagEnd::Item, TagEnd::Paragraph]
+        ),
+        Event::Code(_)
+        | Event::InlineMath(_)
+        | Event::DisplayMath(_)
+        | Event::InlineHtml(_)
+        | Event::FootnoteReference(_)
+        | Event::SoftBreak
+        | Event::HardBreak => holds_inline(parent),
+    }
+}
+
+/// The container to open within the one closed by `parent`, or the document if it's `None`, for `event` to fit
+/// within it or within the containers it needs in turn, if there is one.
+fn wrapper(parent: Option<&TagEnd>, event: &Event<'_>) -> Option<Tag<'static>> {
+    match event {
+        Event::Start(Tag::Item) | Event::TaskListMarker(_) if holds_blocks(parent) => Some(Tag::List(None)),
+        Event::TaskListMarker(_) if matches!(parent, Some(TagEnd::List(_))) => Some(Tag::Item),
+        Event::Start(Tag::DefinitionListTitle | Tag::DefinitionListDefinition) if holds_blocks(parent) => {
+            Some(Tag::DefinitionList)
+        }
+        Event::Start(Tag::TableCell) if parent == Some(&TagEnd::Table) => Some(Tag::TableRow),
+        Event::Html(_) if holds_blocks(parent) => Some(Tag::HtmlBlock),
+        event if is_inline_event(event) => match parent {
+            _ if holds_blocks(parent) => Some(Tag::Paragraph),
+            Some(TagEnd::List(_)) => Some(Tag::Item),
+            Some(TagEnd::TableHead | TagEnd::TableRow) => Some(Tag::TableCell),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns `true` if `event` is inline content, which can only appear within blocks.
+fn is_inline_event(event: &Event<'_>) -> bool {
+    match event {
+        Event::Start(tag) => is_inline(&tag.to_end()),
+        Event::Text(_)
+        | Event::Code(_)
+        | Event::InlineMath(_)
+        | Event::DisplayMath(_)
+        | Event::InlineHtml(_)
+        | Event::FootnoteReference(_)
+        | Event::SoftBreak
+        | Event::HardBreak => true,
+        _ => false,
+    }
+}
+
+/// Returns `true` if the container closed by `parent`, or the document if it's `None`, can directly contain blocks.
+fn holds_blocks(parent: Option<&TagEnd>) -> bool {
+    matches!(
+        parent,
+        None | Some(
+            TagEnd::BlockQuote(_) | TagEnd::Item | TagEnd::FootnoteDefinition | TagEnd::DefinitionListDefinition
+        )
+    )
+}
+
+/// Returns `true` if the container closed by `parent` can directly contain inline elements.
+fn holds_inline(parent: Option<&TagEnd>) -> bool {
+    parent.is_some_and(|tag| {
+        is_inline(tag)
+            || matches!(
+                tag,
+                TagEnd::Paragraph
+                    | TagEnd::Heading(_)
+                    | TagEnd::Item
+                    | TagEnd::TableCell
+                    | TagEnd::DefinitionListTitle
+                    | TagEnd::DefinitionListDefinition
+            )
+    })
+}
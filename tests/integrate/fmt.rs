@@ -104,7 +104,7 @@ mod lazy_newlines {
         ] {
             let mut state = State::default();
             state.newlines_before_start = 1;
-            assert_eq!(fmte(&[e.clone()]).1, state);
+            assert_eq!(fmte(std::slice::from_ref(e)).1, state);
         }
     }
 
@@ -125,7 +125,7 @@ fn it_applies_newlines_before_start_before_text() {
     first.last_was_text_without_trailing_newline = true;
 
     let mut second = State::default();
-    second.newlines_before_start;
+    second.newlines_before_start = 0;
     second.last_was_text_without_trailing_newline = true;
 
     assert_eq!(fmtes(&[Event::Text("t".into())], first), ("\n\nt".into(), second));
@@ -140,6 +140,9 @@ fn it_applies_newlines_before_start_before_any_start_tag() {
     let mut second = State::default();
     second.newlines_before_start = 0;
     second.last_was_text_without_trailing_newline = true;
+    second.current_line = Some("h".into());
+    second.first_line_of_block = true;
+    second.open_containers = vec![TagEnd::Paragraph];
 
     assert_eq!(
         fmtes(&[Event::Start(Tag::Paragraph), Event::Text("h".into())], first,),
@@ -148,7 +151,7 @@ fn it_applies_newlines_before_start_before_any_start_tag() {
 }
 
 mod padding {
-    use super::{fmtes, Event, State, Tag};
+    use super::{fmtes, Event, State, Tag, TagEnd};
 
     #[test]
     fn is_used_before_newlines() {
@@ -161,6 +164,9 @@ mod padding {
         second.newlines_before_start = 0;
         second.padding = vec!["  ".into()];
         second.last_was_text_without_trailing_newline = true;
+        second.current_line = Some("h".into());
+        second.first_line_of_block = true;
+        second.open_containers = vec![TagEnd::Paragraph];
 
         assert_eq!(
             fmtes(&[Event::Start(Tag::Paragraph), Event::Text("h".into())], first,),
@@ -428,6 +434,7 @@ mod blockquote {
         let mut state = State::default();
         state.newlines_before_start = 1;
         state.padding = vec![" > ".into()];
+        state.open_containers = vec![TagEnd::BlockQuote(None)];
         assert_eq!(fmte(&[Event::Start(Tag::BlockQuote(None)),]).1, state);
     }
 
@@ -634,12 +641,13 @@ mod blockquote {
 }
 
 mod codeblock {
-    use super::{fmte, fmts_both, fmts_with_options, CmarkToCmarkOptions, CodeBlockKind, Event, State, Tag};
+    use super::{fmte, fmts_both, fmts_with_options, CmarkToCmarkOptions, CodeBlockKind, Event, State, Tag, TagEnd};
 
     #[test]
     fn it_keeps_track_of_the_presence_of_a_code_block() {
         let mut state = State::default();
         state.code_block = Some(pulldown_cmark_to_cmark::CodeBlockKind::Fenced);
+        state.open_containers = vec![TagEnd::CodeBlock];
         assert_eq!(
             fmte(&[Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced("s".into()))),]).1,
             state
@@ -724,7 +732,9 @@ mod table {
     use pretty_assertions::assert_eq;
     use pulldown_cmark_to_cmark::Alignment;
 
-    use super::{fmte, fmtes, Alignment as TableAlignment, Event, State, Tag, TagEnd};
+    use super::{
+        fmte, fmtes, fmts_with_options, Alignment as TableAlignment, CmarkToCmarkOptions, Event, State, Tag, TagEnd,
+    };
 
     #[test]
     fn it_forgets_alignments_and_headers_at_the_end_of_tables() {
@@ -743,6 +753,7 @@ mod table {
         let mut state = State::default();
         state.table_alignments = vec![Alignment::None, Alignment::Center];
         state.table_headers = vec!["a".into(), "b".into()];
+        state.open_containers = vec![TagEnd::Table, TagEnd::TableHead];
         assert_eq!(
             fmte(&[
                 Event::Start(Tag::Table(vec![TableAlignment::None, TableAlignment::Center])),
@@ -828,6 +839,78 @@ mod table {
 
         assert_eq!(original_events, generated_events);
     }
+    #[test]
+    fn it_aligns_columns_if_configured() {
+        use pulldown_cmark::{Options, Parser};
+
+        let original_table_markdown = indoc!(
+            r"
+            | Tables | Are | Cool | yo ||
+            |--|:-:|--:|:-|-|
+            | col 3 is | right-aligned | $1600 | x |01|
+            | `a\|b` *em* | centered | $12 | y |02|"
+        );
+        let options = CmarkToCmarkOptions {
+            align_table_columns: true,
+            ..Default::default()
+        };
+        let (generated_markdown, state) = fmts_with_options(original_table_markdown, options);
+
+        assert_eq!(
+            generated_markdown,
+            indoc!(
+                r"
+                | Tables      |      Are      |  Cool | yo  |     |
+                | ----------- | :-----------: | ----: | :-- | --- |
+                | col 3 is    | right-aligned | $1600 | x   | 01  |
+                | `a\|b` *em* |   centered    |   $12 | y   | 02  |"
+            )
+        );
+        assert!(
+            state.table_rows.is_empty(),
+            "rows are forgotten once the table is written"
+        );
+
+        let original_events: Vec<_> = Parser::new_ext(original_table_markdown, Options::all()).collect();
+        let generated_events: Vec<_> = Parser::new_ext(&generated_markdown, Options::all()).collect();
+        assert_eq!(original_events, generated_events);
+    }
+
+    #[test]
+    #[cfg(feature = "unicode-width")]
+    fn it_measures_cells_by_display_width() {
+        let options = CmarkToCmarkOptions {
+            align_table_columns: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options("|名前|e\u{301}|👩‍🔬|\n|-|-|-|\n|a|b|c|", options).0,
+            indoc!(
+                "
+                | 名前 | e\u{301}   | 👩‍🔬  |
+                | ---- | --- | --- |
+                | a    | b   | c   |"
+            )
+        );
+        assert_eq!(
+            fmts_with_options("|名前|\n|-|", Default::default()).0,
+            "|名前|\n|----|",
+            "delimiter rows match the display width of the header"
+        );
+    }
+
+    #[test]
+    fn aligned_tables_keep_the_padding_of_their_container() {
+        let options = CmarkToCmarkOptions {
+            align_table_columns: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options("* | a | b |\n  |---|---|\n  | cc | ddddd |", options).0,
+            "* | a   | b     |\n  | --- | ----- |\n  | cc  | ddddd |"
+        );
+    }
+
     #[test]
     fn table_with_pipe_in_column() {
         use pulldown_cmark::{Options, Parser};
@@ -864,7 +947,10 @@ mod escapes {
     use pulldown_cmark::CowStr;
 
     use super::source_range;
-    use crate::{fmt::fmts, fmt::fmts_both, fmt::CmarkToCmarkOptions, fmt::Event, fmt::Parser, fmt::Tag, fmt::TagEnd};
+    use crate::{
+        fmt::fmts, fmt::fmts_both, fmt::fmts_with_options, fmt::CmarkToCmarkOptions, fmt::Event, fmt::Parser, fmt::Tag,
+        fmt::TagEnd,
+    };
 
     fn run_test_on_each_special_char(f: impl Fn(String, CowStr)) {
         for c in CmarkToCmarkOptions::default().special_characters().chars() {
@@ -875,10 +961,7 @@ mod escapes {
 
     #[test]
     fn it_does_not_recreate_escapes_for_underscores_in_the_middle_of_a_word() {
-        assert_eq!(
-            fmts("\\_hello_world_").0,
-            "\\_hello_world\\_" // it actually makes mal-formatted markdown better
-        );
+        assert_eq!(fmts("\\_hello_world_").0, "\\_hello_world_");
     }
 
     #[test]
@@ -886,6 +969,11 @@ mod escapes {
         assert_eq!(source_range::fmts("\\_hello_world_").0, "\\_hello_world_");
     }
 
+    #[test]
+    fn it_keeps_links_after_an_exclamation_mark_from_becoming_images() {
+        assert_eq!(fmts_both(r"a\![b](c)").0, r"a\![b](c)");
+    }
+
     #[test]
     fn it_recreates_escapes_for_known_special_characters_at_the_beginning_of_the_word() {
         run_test_on_each_special_char(|escaped_special_character, _| {
@@ -1041,11 +1129,298 @@ mod escapes {
         source_range::assert_events_eq("&#32;   foo");
         source_range::assert_events_eq(" * &#32;   foo\n * &#9;foo");
     }
+
+    /// Serialize `md` with its text merged, and assert it parses into the same events again.
+    fn fmts_merged(md: &str) -> String {
+        use pulldown_cmark::{utils::TextMergeStream, Options};
+
+        let events = || TextMergeStream::new(Parser::new_ext(md, Options::all())).collect::<Vec<_>>();
+        let mut buf = String::new();
+        pulldown_cmark_to_cmark::cmark(events().into_iter(), &mut buf).unwrap();
+        let reparsed = TextMergeStream::new(Parser::new_ext(&buf, Options::all())).collect::<Vec<_>>();
+        assert_eq!(events(), reparsed, "{}", buf);
+        buf
+    }
+
+    #[test]
+    fn block_markers_are_escaped_at_the_start_of_each_line() {
+        assert_eq!(fmts_merged("1986\\. was a year"), "1986\\. was a year");
+        assert_eq!(fmts_merged("\\- a\n\\+ b"), "\\- a\n\\+ b");
+        assert_eq!(
+            fmts_merged("a\n\\- b\n2. c\n1\\. d\n\\==="),
+            "a\n\\- b\n2. c\n1\\. d\n\\==="
+        );
+        assert_eq!(fmts_merged("a\n2) c\n\\~~~"), "a\n2) c\n\\~~~");
+        assert_eq!(fmts_merged("* \\+ a\n  1\\) b\n  2) c"), "* \\+ a\n  1\\) b\n  2) c");
+        assert_eq!(fmts_merged("> \\# a\n> \\-"), "\n > \n > \\# a\n > \\-");
+    }
+
+    #[test]
+    fn inline_delimiters_are_escaped_where_they_could_open_or_close_a_construct() {
+        let text = "a <div> `x` *b* _c_ d_e_f [g](h) ![i](j) 2 * 3 a < b";
+        let mut buf = String::new();
+        pulldown_cmark_to_cmark::cmark(
+            [
+                Event::Start(Tag::Paragraph),
+                Event::Text(text.into()),
+                Event::End(TagEnd::Paragraph),
+            ]
+            .iter(),
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(buf, r"a \<div> \`x\` \*b\* \_c_ d_e_f \[g](h) !\[i](j) 2 * 3 a < b");
+        assert_eq!(fmts_merged(&buf), buf);
+    }
+
+    #[test]
+    fn brackets_are_escaped_if_they_enclose_a_known_label() {
+        assert_eq!(
+            fmts_merged("[l] and \\[l] and [m]\n\n[l]: u"),
+            "[l] and \\[l] and [m]\n\n[l]: u"
+        );
+    }
+
+    #[test]
+    fn entity_references_are_escaped() {
+        assert_eq!(
+            fmts_merged(r"\&copy; \&#35; \&#x23; &amp; & &x &#; &#12345678;"),
+            r"\&copy; \&#35; \&#x23; & & &x &#; &#12345678;"
+        );
+    }
+
+    #[test]
+    fn invisible_characters_can_be_written_as_numeric_references() {
+        let options = CmarkToCmarkOptions {
+            numeric_character_references: true,
+            ..Default::default()
+        };
+        let (buf, _) = fmts_with_options("a\u{a0}b\u{200b}c&#1;\td", options);
+        assert_eq!(buf, "a&#160;b&#8203;c&#1;\td");
+        assert_eq!(fmts("a\u{a0}b").0, "a\u{a0}b", "it is off by default");
+    }
+
+    #[test]
+    fn output_does_not_depend_on_how_text_is_split_into_events() {
+        use pulldown_cmark::{utils::TextMergeStream, Options};
+
+        for md in [
+            "1986\\. was a *year* and \\- a [link](u) \\[x] `c`",
+            "\\# a\n\\+ b\n\\=== c\\_d\\_",
+            "# a \\#\n\n> \\* b \\<div> &amp;copy;",
+            "| a \\| b |\n|---|\n| \\* |",
+        ] {
+            let merged: Vec<_> = TextMergeStream::new(Parser::new_ext(md, Options::all())).collect();
+            let split: Vec<_> = merged
+                .iter()
+                .flat_map(|event| match event {
+                    Event::Text(text) => text.chars().map(|c| Event::Text(c.to_string().into())).collect(),
+                    event => vec![event.clone()],
+                })
+                .collect();
+            let (mut from_merged, mut from_split) = (String::new(), String::new());
+            pulldown_cmark_to_cmark::cmark(merged.iter(), &mut from_merged).unwrap();
+            pulldown_cmark_to_cmark::cmark(split.iter(), &mut from_split).unwrap();
+            assert_eq!(from_merged, from_split);
+        }
+    }
+
+    #[test]
+    fn block_markers_are_kept_where_they_cannot_start_a_block() {
+        assert_eq!(fmts_merged("# - a"), "# - a");
+        assert_eq!(fmts_merged("| 1. a |\n|---|\n| - b |"), "|1. a|\n|----|\n|- b|");
+        assert_eq!(fmts_merged("-$x$ -`a`"), "-$x$ -`a`");
+        assert_eq!(fmts_merged("a - b 1. c"), "a - b 1. c");
+    }
+
+    #[test]
+    fn extension_delimiters_are_escaped_if_the_extension_is_enabled() {
+        use pulldown_cmark::{utils::TextMergeStream, Options};
+
+        let extensions = Options::all() - Options::ENABLE_SMART_PUNCTUATION;
+        for (md, parser_options, expected) in [
+            (
+                "$x$ ~y~ ^z^ a | b [^1] [[w]]",
+                extensions,
+                r"\$x\$ \~y\~ \^z^ a \| b \[\^1] \[[w]]",
+            ),
+            ("> [!NOTE]\\\n> a", extensions, "\n > \n > \\[!NOTE]  \n > a"),
+            ("a\\\n: b", extensions, "a  \n\\: b"),
+            ("* [ ] a", extensions, "* \\[ ] a"),
+            ("# a {#b}", extensions, "# a {#b\\}"),
+            // Nothing is escaped for plain CommonMark.
+            ("$x$ ~y~ a | b [^1] [[w]]", Options::empty(), "$x$ ~y~ a | b [^1] [[w]]"),
+        ] {
+            // Parsed as plain CommonMark, the delimiters of the extensions are text.
+            let events = TextMergeStream::new(Parser::new(md)).collect::<Vec<_>>();
+            let options = CmarkToCmarkOptions {
+                parser_options,
+                ..Default::default()
+            };
+            let mut buf = String::new();
+            pulldown_cmark_to_cmark::cmark_with_options(events.iter(), &mut buf, options).unwrap();
+            assert_eq!(buf, expected);
+            let reparsed = TextMergeStream::new(Parser::new_ext(&buf, parser_options)).collect::<Vec<_>>();
+            assert_eq!(events, reparsed, "{}", buf);
+        }
+    }
+
+    #[test]
+    fn escape_policy_controls_how_eagerly_text_is_escaped() {
+        use pulldown_cmark_to_cmark::EscapePolicy;
+
+        let fmt_with_policy = |events: &[Event<'_>], escape_policy| {
+            let options = CmarkToCmarkOptions {
+                escape_policy,
+                ..Default::default()
+            };
+            let mut buf = String::new();
+            pulldown_cmark_to_cmark::cmark_with_options(events.iter(), &mut buf, options).unwrap();
+            buf
+        };
+        let paragraph = [
+            Event::Start(Tag::Paragraph),
+            Event::Text(r"#a *b* [c] d_e 1+1=2 f\g\".into()),
+            Event::End(Tag::Paragraph.to_end()),
+        ];
+        let heading = [
+            Event::Start(Tag::Heading {
+                level: pulldown_cmark::HeadingLevel::H1,
+                id: None,
+                classes: vec![],
+                attrs: vec![],
+            }),
+            Event::Text("a# b#".into()),
+            Event::End(TagEnd::Heading(pulldown_cmark::HeadingLevel::H1)),
+        ];
+        let definition = [
+            Event::Start(Tag::Paragraph),
+            Event::Text("[a]: b".into()),
+            Event::End(Tag::Paragraph.to_end()),
+        ];
+        for (policy, expected_paragraph, expected_heading, expected_definition) in [
+            (
+                EscapePolicy::Minimal,
+                r"#a \*b\* [c] d_e 1+1=2 f\g\\",
+                "# a# b#",
+                r"\[a]: b",
+            ),
+            (
+                EscapePolicy::Conservative,
+                r"\#a \*b\* [c] d_e 1+1=2 f\g\\",
+                r"# a# b\#",
+                r"\[a]: b",
+            ),
+            (
+                EscapePolicy::Paranoid,
+                r"\#a \*b\* \[c\] d\_e 1\+1\=2 f\\g\\",
+                r"# a\# b\#",
+                r"\[a\]\: b",
+            ),
+        ] {
+            assert_eq!(fmt_with_policy(&paragraph, policy), expected_paragraph, "{:?}", policy);
+            assert_eq!(fmt_with_policy(&heading, policy), expected_heading, "{:?}", policy);
+            assert_eq!(
+                fmt_with_policy(&definition, policy),
+                expected_definition,
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn super_and_subscript_delimiters_are_escaped_if_they_are_written_as_markers() {
+        let options = CmarkToCmarkOptions {
+            use_html_for_super_sub_script: false,
+            ..Default::default()
+        };
+        let mut buf = String::new();
+        pulldown_cmark_to_cmark::cmark_with_options(
+            [
+                Event::Start(Tag::Paragraph),
+                Event::Text("^a^ ~b~".into()),
+                Event::End(TagEnd::Paragraph),
+            ]
+            .iter(),
+            &mut buf,
+            options,
+        )
+        .unwrap();
+        assert_eq!(buf, r"\^a\^ \~b\~");
+    }
+}
+
+mod optimized_escapes {
+    use pulldown_cmark::{Options, Parser};
+    use pulldown_cmark_to_cmark::{cmark_with_optimized_escapes, EscapePolicy};
+
+    use super::{CmarkToCmarkOptions, Event, Tag, TagEnd};
+
+    #[test]
+    fn escapes_are_removed_if_they_are_not_needed() {
+        let mut buf = String::new();
+        cmark_with_optimized_escapes(
+            Parser::new(r"\#a \*b\* \[c] d\_"),
+            &mut buf,
+            CmarkToCmarkOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(buf, r"#a \*b* [c] d_");
+
+        let mut buf = String::new();
+        cmark_with_optimized_escapes(Parser::new("# a \\# b\\#"), &mut buf, CmarkToCmarkOptions::default()).unwrap();
+        assert_eq!(buf, "# a # b#");
+    }
+
+    #[test]
+    fn escapes_are_added_where_the_output_does_not_parse_into_the_events() {
+        let events = [
+            Event::Start(Tag::Paragraph),
+            Event::Text("1986".into()),
+            Event::Text(". was a *year".into()),
+            Event::End(TagEnd::Paragraph),
+        ];
+        let mut buf = String::new();
+        cmark_with_optimized_escapes(events.iter(), &mut buf, CmarkToCmarkOptions::default()).unwrap();
+        assert_eq!(buf, r"1986\. was a *year");
+    }
+
+    #[test]
+    fn no_escapes_are_added_if_they_cannot_fix_the_output() {
+        let options = CmarkToCmarkOptions {
+            escape_policy: EscapePolicy::Minimal,
+            ..Default::default()
+        };
+        let mut buf = String::new();
+        cmark_with_optimized_escapes(Parser::new("*_foo_*"), &mut buf, options).unwrap();
+        assert_eq!(
+            buf, "**foo**",
+            "nested emphasis is written as strong emphasis either way"
+        );
+    }
+
+    #[test]
+    fn escapes_of_extensions_are_kept_if_they_are_enabled() {
+        let options = CmarkToCmarkOptions {
+            parser_options: Options::ENABLE_STRIKETHROUGH,
+            ..Default::default()
+        };
+        let mut buf = String::new();
+        cmark_with_optimized_escapes(Parser::new("~~a~~ \\~b~"), &mut buf, options).unwrap();
+        assert_eq!(buf, r"\~~a~~ \~b~");
+    }
 }
 
 mod list {
-    use super::{fmtes, fmts_both, fmts_with_options, CmarkToCmarkOptions, Event, State, TagEnd};
+    use super::{
+        assert_events_eq_both, cmark_resume_with_options, fmtes, fmts_both, fmts_with_options, CmarkToCmarkOptions,
+        Event, Parser, State, Tag, TagEnd,
+    };
     use indoc::indoc;
+    use pulldown_cmark_to_cmark::{
+        cmark, Error, ListIndentation, ListNumberPadding, ListSpacing, OrderedListNumbering,
+    };
 
     #[test]
     fn it_pops_one_item_from_the_lists_stack_for_each_end_list() {
@@ -1110,6 +1485,71 @@ mod list {
         assert_eq!(s, "- a\n- b".to_string());
     }
 
+    #[test]
+    fn nested_list_tokens_cycle_by_depth() {
+        let custom_options = CmarkToCmarkOptions {
+            nested_list_tokens: "-*+",
+            ..Default::default()
+        };
+        let original = "* a\n  1. b\n     * c\n       * d\n* e";
+        assert_eq!(
+            fmts_with_options(original, custom_options).0,
+            "- a\n  1. b\n     + c\n       - d\n- e"
+        );
+    }
+
+    #[test]
+    fn adjacent_lists_alternate_their_markers() {
+        let original = "- a\n\n* b\n\n- c\n\n1. d\n\n1) e\n\n* f";
+        assert_eq!(fmts_both(original).0, "* a\n\n- b\n\n* c\n\n1. d\n\n1) e\n\n* f");
+        assert_events_eq_both(original);
+
+        let nested = "* a\n  - b\n\n  + c\n* d";
+        assert_eq!(fmts_both(nested).0, "* a\n  \n  * b\n  - c\n* d");
+        assert_events_eq_both(nested);
+    }
+
+    #[test]
+    fn tight_list_spacing_removes_blank_lines_between_items() {
+        let custom_options = CmarkToCmarkOptions {
+            list_spacing: ListSpacing::Tight,
+            ..Default::default()
+        };
+        let (output, state) = fmts_with_options("* a\n\n* b\n\n  * c\n\n  * d\n\n* e\n\nafter", custom_options);
+        assert_eq!(output, "* a\n* b\n  * c\n  * d\n* e\n\nafter");
+        assert_eq!(state.blank_lines_kept_in_tight_lists, 0);
+    }
+
+    #[test]
+    fn tight_list_spacing_keeps_blank_lines_between_blocks_that_would_merge() {
+        let custom_options = CmarkToCmarkOptions {
+            list_spacing: ListSpacing::Tight,
+            ..Default::default()
+        };
+        let (output, state) = fmts_with_options(
+            "* a\n\n  b\n* c\n\n  ```\n  x\n  ```\n\n  d\n* e\n\n  * f\n\n  g",
+            custom_options,
+        );
+        assert_eq!(
+            output,
+            "* a\n  \n  b\n* c\n  ````\n  x\n  ````\n  d\n* e\n  * f\n  \n  g"
+        );
+        assert_eq!(state.blank_lines_kept_in_tight_lists, 2);
+    }
+
+    #[test]
+    fn loose_list_spacing_puts_one_blank_line_between_items() {
+        let custom_options = CmarkToCmarkOptions {
+            list_spacing: ListSpacing::Loose,
+            newlines_after_paragraph: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options("1. a\n2. b\n   - c\n   - d\n3. e", custom_options).0,
+            "1. a\n\n1. b\n   * c\n   \n   * d\n\n1. e"
+        );
+    }
+
     #[test]
     fn ordered() {
         let mut state = State::default();
@@ -1132,6 +1572,7 @@ mod list {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn increment_ordered_list_bullets() {
         let custom_options = CmarkToCmarkOptions {
             increment_ordered_list_bullets: true,
@@ -1146,6 +1587,7 @@ mod list {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn nested_increment_ordered_list_bullets() {
         let custom_options = CmarkToCmarkOptions {
             increment_ordered_list_bullets: true,
@@ -1176,6 +1618,7 @@ mod list {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn nested_increment_ordered_list_bullets_change_ordered_list_token() {
         let custom_options = CmarkToCmarkOptions {
             increment_ordered_list_bullets: true,
@@ -1218,6 +1661,132 @@ mod list {
         assert_eq!(fmts_with_options(input, custom_options), (expected.into(), state));
     }
 
+    #[test]
+    fn ordered_list_numbering() {
+        let numbered = |ordered_list_numbering| {
+            fmts_with_options(
+                "3. a\n3. b\n3. c",
+                CmarkToCmarkOptions {
+                    ordered_list_numbering,
+                    ..Default::default()
+                },
+            )
+            .0
+        };
+        assert_eq!(numbered(OrderedListNumbering::Same), "3. a\n3. b\n3. c");
+        assert_eq!(numbered(OrderedListNumbering::Sequential), "3. a\n4. b\n5. c");
+        assert_eq!(numbered(OrderedListNumbering::FromOne), "1. a\n2. b\n3. c");
+    }
+
+    #[test]
+    fn continuation_lines_are_padded_to_the_width_of_each_marker() {
+        let custom_options = CmarkToCmarkOptions {
+            ordered_list_numbering: OrderedListNumbering::Sequential,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options("9. a\n   * x\n9. b\n   * y", custom_options).0,
+            "9. a\n   * x\n10. b\n    * y"
+        );
+    }
+
+    #[test]
+    fn ordered_list_number_padding() {
+        let padded = |ordered_list_number_padding| {
+            fmts_with_options(
+                "8. a\n8. b\n   * x\n8. c\n\n- d",
+                CmarkToCmarkOptions {
+                    ordered_list_numbering: OrderedListNumbering::Sequential,
+                    ordered_list_number_padding,
+                    ..Default::default()
+                },
+            )
+            .0
+        };
+        assert_eq!(padded(ListNumberPadding::Unpadded), "8. a\n9. b\n   * x\n10. c\n\n* d");
+        assert_eq!(padded(ListNumberPadding::Spaces), " 8. a\n 9. b\n    * x\n10. c\n\n* d");
+        assert_eq!(padded(ListNumberPadding::Zeros), "08. a\n09. b\n    * x\n10. c\n\n* d");
+    }
+
+    #[test]
+    fn lists_open_when_suspending_are_padded_to_the_items_up_to_there() {
+        let options = || CmarkToCmarkOptions {
+            ordered_list_numbering: OrderedListNumbering::Sequential,
+            ordered_list_number_padding: ListNumberPadding::Zeros,
+            ..Default::default()
+        };
+        let events: Vec<_> = Parser::new("8. a\n8. b\n8. c").collect();
+        let mut buf = String::new();
+        let state = cmark_resume_with_options(events[..6].iter(), &mut buf, None, options()).unwrap();
+        cmark_resume_with_options(events[6..].iter(), &mut buf, Some(state), options()).unwrap();
+        assert_eq!(buf, "8. a\n9. b\n10. c");
+
+        let mut buf = String::new();
+        cmark_resume_with_options(events.iter(), &mut buf, None, options()).unwrap();
+        assert_eq!(buf, "08. a\n09. b\n10. c");
+    }
+
+    #[test]
+    fn numbers_with_more_than_nine_digits_are_rejected() {
+        let mut buf = String::new();
+        let Err(Error::ListNumberOutOfRange(number)) = cmark(
+            [Event::Start(Tag::List(Some(1_000_000_000))), Event::Start(Tag::Item)].iter(),
+            &mut buf,
+        ) else {
+            panic!("the number has ten digits")
+        };
+        assert_eq!(number, 1_000_000_000);
+
+        let custom_options = CmarkToCmarkOptions {
+            ordered_list_numbering: OrderedListNumbering::Sequential,
+            ..Default::default()
+        };
+        let Err(err) = cmark_resume_with_options(
+            Parser::new("999999999. a\n999999999. b"),
+            &mut buf,
+            None,
+            custom_options,
+        ) else {
+            panic!("the second item would be numbered with ten digits")
+        };
+        assert_eq!(
+            err.to_string(),
+            "The list item number 1000000000 has more than the 9 digits Markdown allows"
+        );
+    }
+
+    #[test]
+    fn list_indentation_in_columns() {
+        let custom_options = CmarkToCmarkOptions {
+            list_indentation: ListIndentation::Columns(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options(
+                "* a\n  * b\n\n        code\n\n  > quote\n10. c\n\n    100. d\n\n         e\n*     code",
+                custom_options
+            )
+            .0,
+            "*   a\n    \n    *   b\n        \n            code\n            \n    \n     > \n     > quote\n\n10. c\n    \n    100. d\n         \n         e\n\n* \n      code\n      "
+        );
+    }
+
+    #[test]
+    fn list_indentation_with_tabs() {
+        let custom_options = CmarkToCmarkOptions {
+            list_indentation: ListIndentation::Tab,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options(
+                "* a\n  * b\n\n  > * c\n  >   d\n1. e\n\n   100. f\n\n        g",
+                custom_options
+            )
+            .0,
+            "*\ta\n\t\n\t*\tb\n\t > \n\t > *\tc\n\t > \t\td\n\n1.\te\n\t\n\t100.\tf\n\t\t\t\n\t\t\tg"
+        );
+    }
+
     #[test]
     fn checkboxes() {
         assert_eq!(
@@ -1234,7 +1803,71 @@ mod list {
 }
 
 mod heading {
-    use super::assert_events_eq_both;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use pulldown_cmark_to_cmark::HeadingStyle;
+
+    use super::{assert_events_eq_both, fmts_with_options, CmarkToCmarkOptions};
+
+    #[test]
+    fn setext_headings_are_underlined_to_the_width_of_their_content() {
+        let options = CmarkToCmarkOptions {
+            heading_style: HeadingStyle::Setext,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options("# Title *em*\n\nFoo\nbar baz\n===\n\n## Two\n\n### Three", options).0,
+            indoc!(
+                "
+                Title *em*
+                ==========
+
+                Foo
+                bar baz
+                =======
+
+                Two
+                ---
+
+                ### Three"
+            )
+        );
+    }
+
+    #[test]
+    fn setext_headings_keep_padding_and_attributes() {
+        let options = CmarkToCmarkOptions {
+            heading_style: HeadingStyle::Setext,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options("* # Multi {#id .c}\n\n> a\n> b\n> ---", options).0,
+            "* Multi { #id .c }\n  ================\n\n > \n > a\n > b\n > -"
+        );
+    }
+
+    #[test]
+    fn setext_falls_back_to_atx_if_content_cannot_be_underlined() {
+        let options = CmarkToCmarkOptions {
+            heading_style: HeadingStyle::Setext,
+            ..Default::default()
+        };
+        assert_eq!(fmts_with_options("#", options.clone()).0, "# ");
+        assert_eq!(fmts_with_options("# \\- foo", options.clone()).0, "\\- foo\n======");
+        assert_eq!(fmts_with_options("a\n\\> b\n===", options).0, "a\n\\> b\n====");
+    }
+
+    #[test]
+    fn closed_atx_headings() {
+        let options = CmarkToCmarkOptions {
+            heading_style: HeadingStyle::ClosedAtx,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options("# Title #\n\n## Two {#id}\n\nThree\n---", options).0,
+            "# Title #\n\n## Two ## { #id }\n\n## Three ##"
+        );
+    }
 
     #[test]
     fn heading_with_classes_and_attrs() {
@@ -1249,6 +1882,565 @@ mod heading {
     }
 }
 
+mod rule {
+    use pretty_assertions::assert_eq;
+
+    use super::{fmts_with_options, CmarkToCmarkOptions};
+
+    #[test]
+    fn token_count_and_spacing_are_configurable() {
+        let options = CmarkToCmarkOptions {
+            rule_token: '*',
+            ..Default::default()
+        };
+        assert_eq!(fmts_with_options("a\n\n---\n\nb", options).0, "a\n\n***\n\nb");
+
+        let options = CmarkToCmarkOptions {
+            rule_token: '_',
+            rule_token_count: 5,
+            rule_token_spacing: 1,
+            ..Default::default()
+        };
+        assert_eq!(fmts_with_options("> ---", options).0, "\n > \n > _ _ _ _ _");
+
+        let options = CmarkToCmarkOptions {
+            rule_token_count: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options("***", options).0,
+            "---",
+            "rules need at least 3 tokens"
+        );
+    }
+
+    #[test]
+    fn tokens_which_cannot_make_up_a_rule_fall_back_to_dashes() {
+        let options = CmarkToCmarkOptions {
+            rule_token: 'x',
+            ..Default::default()
+        };
+        assert_eq!(fmts_with_options("a\n\n***", options).0, "a\n\n---");
+    }
+
+    #[test]
+    fn it_is_never_read_as_setext_underline() {
+        for newlines_after_paragraph in 1..=2 {
+            let options = CmarkToCmarkOptions {
+                newlines_after_paragraph,
+                ..Default::default()
+            };
+            assert_eq!(
+                fmts_with_options("a\n\n***\n\n* b\n\n  ***", options).0,
+                "a\n\n---\n\n* b\n  \n  ---"
+            );
+        }
+
+        let options = CmarkToCmarkOptions {
+            newlines_after_paragraph: 1,
+            rule_token_spacing: 1,
+            ..Default::default()
+        };
+        assert_eq!(fmts_with_options("a\n- - -", options).0, "a\n- - -");
+    }
+}
+
+mod reference_definitions {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use pulldown_cmark::HeadingLevel;
+    use pulldown_cmark_to_cmark::{ReferenceDefinitionOrder, ReferenceDefinitionPlacement};
+
+    use super::{cmark_resume_with_options, fmts_with_options, CmarkToCmarkOptions, Options, Parser};
+
+    const DOCUMENT: &str = indoc!(
+        "
+        # A
+
+        [x] and ![y]
+
+        > [z]
+
+        ## B
+
+        ![Y][y] [w]
+
+        ### C
+
+        [x][]
+
+        [w]: u4
+        [x]: u1
+        [y]: u2
+        [z]: u3"
+    );
+
+    #[test]
+    fn after_the_top_level_block_of_first_use() {
+        let options = CmarkToCmarkOptions {
+            reference_definition_placement: ReferenceDefinitionPlacement::AfterBlock,
+            ..Default::default()
+        };
+        assert_eq!(
+            fmts_with_options(DOCUMENT, options).0,
+            indoc!(
+                "
+                # A
+
+                [x] and ![y]
+
+                [x]: u1
+                [y]: u2
+
+                 > 
+                 > [z]
+
+                [z]: u3
+
+                ## B
+
+                ![Y][y] [w]
+
+                [w]: u4
+
+                ### C
+
+                [x][]"
+            )
+        );
+    }
+
+    #[test]
+    fn at_the_end_of_sections() {
+        let options = CmarkToCmarkOptions {
+            reference_definition_placement: ReferenceDefinitionPlacement::EndOfSection(HeadingLevel::H2),
+            ..Default::default()
+        };
+        let (mut buf, state) = fmts_with_options(DOCUMENT, options);
+        state.finalize(&mut buf).unwrap();
+        assert_eq!(
+            buf,
+            indoc!(
+                "
+                # A
+
+                [x] and ![y]
+
+                 > 
+                 > [z]
+
+                [x]: u1
+                [y]: u2
+                [z]: u3
+
+                ## B
+
+                ![Y][y] [w]
+
+                ### C
+
+                [x][]
+
+                [w]: u4"
+            )
+        );
+    }
+
+    #[test]
+    fn in_deterministic_order() {
+        let options = CmarkToCmarkOptions {
+            reference_definition_order: ReferenceDefinitionOrder::Alphabetical,
+            ..Default::default()
+        };
+        let (mut buf, state) = fmts_with_options(DOCUMENT, options);
+        state.finalize(&mut buf).unwrap();
+        assert!(buf.ends_with("[x][]\n\n[w]: u4\n[x]: u1\n[y]: u2\n[z]: u3"), "{}", buf);
+
+        let options = CmarkToCmarkOptions {
+            reference_definition_order: ReferenceDefinitionOrder::LinksBeforeImages,
+            ..Default::default()
+        };
+        let (mut buf, state) = fmts_with_options(DOCUMENT, options);
+        state.finalize(&mut buf).unwrap();
+        assert!(buf.ends_with("[x][]\n\n[x]: u1\n[z]: u3\n[w]: u4\n[y]: u2"), "{}", buf);
+    }
+
+    #[test]
+    fn resumed_serialization_places_them_the_same_way() {
+        let options = CmarkToCmarkOptions {
+            reference_definition_placement: ReferenceDefinitionPlacement::AfterBlock,
+            reference_definition_order: ReferenceDefinitionOrder::Alphabetical,
+            ..Default::default()
+        };
+        let (mut expected, state) = fmts_with_options(DOCUMENT, options.clone());
+        state.finalize(&mut expected).unwrap();
+        let events: Vec<_> = Parser::new_ext(DOCUMENT, Options::all()).collect();
+        for split_at in 0..events.len() {
+            let mut buf = String::new();
+            let state = cmark_resume_with_options(events[..split_at].iter(), &mut buf, None, options.clone()).unwrap();
+            cmark_resume_with_options(events[split_at..].iter(), &mut buf, Some(state), options.clone())
+                .unwrap()
+                .finalize(&mut buf)
+                .unwrap();
+            assert_eq!(buf, expected, "split at {split_at}");
+        }
+    }
+}
+
+mod hard_break {
+    use pretty_assertions::assert_eq;
+    use pulldown_cmark_to_cmark::{cmark_with_options, HardBreakStyle, HeadingStyle};
+
+    use super::{fmts_with_options, CmarkToCmarkOptions, Event, Options, Parser, Tag, TagEnd};
+
+    #[test]
+    fn each_style_round_trips() {
+        for (hard_break_style, expected, expected_heading) in [
+            (HardBreakStyle::TrailingSpaces, "* a  \n  b", "a  \nb\n==="),
+            (HardBreakStyle::Backslash, "* a\\\n  b", "a\\\nb\n=="),
+        ] {
+            let md = "* a\\\n  b";
+            let options = CmarkToCmarkOptions {
+                hard_break_style,
+                ..Default::default()
+            };
+            let buf = fmts_with_options(md, options).0;
+            assert_eq!(buf, expected);
+            assert_eq!(
+                Parser::new_ext(md, Options::all()).collect::<Vec<_>>(),
+                Parser::new_ext(&buf, Options::all()).collect::<Vec<_>>()
+            );
+
+            let md = "a\\\nb\n===";
+            let options = CmarkToCmarkOptions {
+                hard_break_style,
+                heading_style: HeadingStyle::Setext,
+                ..Default::default()
+            };
+            let buf = fmts_with_options(md, options).0;
+            assert_eq!(buf, expected_heading);
+            assert_eq!(
+                Parser::new_ext(md, Options::all()).collect::<Vec<_>>(),
+                Parser::new_ext(&buf, Options::all()).collect::<Vec<_>>()
+            );
+        }
+        let options = CmarkToCmarkOptions {
+            hard_break_style: HardBreakStyle::Html,
+            ..Default::default()
+        };
+        assert_eq!(fmts_with_options("a  \nb", options).0, "a<br>\nb");
+    }
+
+    #[test]
+    fn html_is_used_where_lines_cannot_break() {
+        for hard_break_style in [HardBreakStyle::TrailingSpaces, HardBreakStyle::Backslash] {
+            let options = CmarkToCmarkOptions {
+                hard_break_style,
+                ..Default::default()
+            };
+            // ATX headings can't span lines.
+            assert_eq!(fmts_with_options("a\\\nb\n===", options.clone()).0, "# a<br>b");
+
+            // Neither can the last line of a setext heading be broken.
+            let heading = Tag::Heading {
+                level: pulldown_cmark::HeadingLevel::H1,
+                id: None,
+                classes: vec![],
+                attrs: vec![],
+            };
+            let mut buf = String::new();
+            cmark_with_options(
+                [
+                    Event::Start(heading),
+                    Event::Text("a".into()),
+                    Event::HardBreak,
+                    Event::End(TagEnd::Heading(pulldown_cmark::HeadingLevel::H1)),
+                ]
+                .iter(),
+                &mut buf,
+                CmarkToCmarkOptions {
+                    heading_style: HeadingStyle::Setext,
+                    ..options.clone()
+                },
+            )
+            .unwrap();
+            assert_eq!(buf, "# a<br>");
+
+            let mut buf = String::new();
+            cmark_with_options(
+                [
+                    Event::Start(Tag::Table(vec![pulldown_cmark::Alignment::None])),
+                    Event::Start(Tag::TableHead),
+                    Event::Start(Tag::TableCell),
+                    Event::Text("a".into()),
+                    Event::HardBreak,
+                    Event::Text("b".into()),
+                    Event::End(TagEnd::TableCell),
+                    Event::End(TagEnd::TableHead),
+                    Event::End(TagEnd::Table),
+                ]
+                .iter(),
+                &mut buf,
+                options,
+            )
+            .unwrap();
+            assert_eq!(buf, "|a<br>b|\n|--|");
+        }
+    }
+}
+
+mod link_style {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use pulldown_cmark_to_cmark::LinkStyle;
+
+    use super::{fmts_with_options, CmarkToCmarkOptions, Event, Options, Parser, Tag};
+
+    const DOCUMENT: &str = indoc!(
+        r#"
+        [a](u1) [b][id] [c] [d][] ![e](u2 "t") [f](u1) [g](u2) <https://h>
+
+        [i](u1 "t") ![j]()
+
+        [id]: u3
+        [c]: u1 "t"
+        [d]: u4"#
+    );
+
+    /// The URI and title of each link and image in `md`.
+    fn destinations(md: &str) -> Vec<(String, String)> {
+        Parser::new_ext(md, Options::all())
+            .filter_map(|event| match event {
+                Event::Start(Tag::Link { dest_url, title, .. } | Tag::Image { dest_url, title, .. }) => {
+                    Some((dest_url.to_string(), title.to_string()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn all_links_can_be_written_inline() {
+        let options = CmarkToCmarkOptions {
+            link_style: LinkStyle::Inline,
+            ..Default::default()
+        };
+        let (mut buf, state) = fmts_with_options(DOCUMENT, options);
+        state.finalize(&mut buf).unwrap();
+        assert_eq!(
+            buf,
+            indoc!(
+                r#"
+                [a](u1) [b](u3) [c](u1 "t") [d](u4) ![e](u2 "t") [f](u1) [g](u2) <https://h>
+
+                [i](u1 "t") ![j]()"#
+            )
+        );
+    }
+
+    #[test]
+    fn all_links_can_be_written_as_references_with_deduplicated_labels() {
+        let options = CmarkToCmarkOptions {
+            link_style: LinkStyle::Reference,
+            ..Default::default()
+        };
+        let (mut buf, state) = fmts_with_options(DOCUMENT, options);
+        state.finalize(&mut buf).unwrap();
+        assert_eq!(
+            buf,
+            indoc!(
+                r#"
+                [a][1] [b][id] [c][c] [d][d] ![e][2] [f][1] [g][3] <https://h>
+
+                [i][c] ![j][4]
+
+                [1]: u1
+                [id]: u3
+                [c]: u1 "t"
+                [d]: u4
+                [2]: u2 "t"
+                [3]: u2
+                [4]: <>"#
+            )
+        );
+    }
+
+    #[test]
+    fn references_are_relabeled_if_their_label_was_generated_for_another_link() {
+        let md = "[1](http://a) [x][1] [1]\n\n[1]: http://b";
+        let options = CmarkToCmarkOptions {
+            link_style: LinkStyle::Reference,
+            ..Default::default()
+        };
+        let (mut buf, state) = fmts_with_options(md, options);
+        state.finalize(&mut buf).unwrap();
+        assert_eq!(buf, "[1][1] [x][2] [1][2]\n\n[1]: http://a\n[2]: http://b");
+        assert_eq!(destinations(&buf), destinations(md));
+    }
+}
+
+mod smart_punctuation {
+    use pretty_assertions::assert_eq;
+    use pulldown_cmark::utils::TextMergeStream;
+    use pulldown_cmark_to_cmark::cmark_with_options;
+
+    use super::{fmts, fmts_with_options, source_range, CmarkToCmarkOptions, Options, Parser};
+
+    #[test]
+    fn is_written_as_unicode_by_default() {
+        assert_eq!(fmts(r#""a" -- b"#).0, "\u{201c}a\u{201d} \u{2013} b");
+    }
+
+    #[test]
+    fn can_be_written_back_in_ascii() {
+        let md = r#"He said "it's -- well --- fine..." 'ok'"#;
+        let options = CmarkToCmarkOptions {
+            reverse_smart_punctuation: true,
+            ..Default::default()
+        };
+        assert_eq!(fmts_with_options(md, options).0, md);
+    }
+
+    #[test]
+    fn is_copied_from_the_source_with_a_source_range() {
+        let md = "\"a\" and \u{201c}b\u{201d} -- c \u{2013} d e----f g------h";
+        let options = CmarkToCmarkOptions {
+            reverse_smart_punctuation: true,
+            ..Default::default()
+        };
+        assert_eq!(source_range::fmts_with_options(md, options.clone()).0, md);
+
+        let mut buf = String::new();
+        cmark_with_options(Parser::new_ext(md, Options::all()), &mut buf, options).unwrap();
+        assert_eq!(buf, r#""a" and "b" -- c -- d e----f g------h"#);
+    }
+
+    #[test]
+    fn ascii_is_escaped_if_the_output_is_parsed_with_smart_punctuation() {
+        let md = "\"a\" -- b... c-\u{2014}";
+        let options = CmarkToCmarkOptions {
+            reverse_smart_punctuation: true,
+            parser_options: Options::ENABLE_SMART_PUNCTUATION,
+            ..Default::default()
+        };
+        let mut buf = String::new();
+        cmark_with_options(Parser::new(md), &mut buf, options).unwrap();
+        assert_eq!(buf, r#"\"a\" \-\- b\.\.\. c\----"#);
+        assert_eq!(
+            TextMergeStream::new(Parser::new_ext(&buf, Options::ENABLE_SMART_PUNCTUATION)).collect::<Vec<_>>(),
+            TextMergeStream::new(Parser::new(md)).collect::<Vec<_>>()
+        );
+    }
+}
+
+mod wrap {
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use pulldown_cmark_to_cmark::{cmark_with_options, WrapMode};
+
+    use super::{fmts_with_options, source_range, CmarkToCmarkOptions, Options, Parser};
+
+    fn wrap(line_width: usize) -> CmarkToCmarkOptions<'static> {
+        CmarkToCmarkOptions {
+            wrap_mode: WrapMode::Wrap,
+            line_width,
+            ..Default::default()
+        }
+    }
+
+    /// The text of `s` with all line breaks turned into spaces.
+    fn words(s: &str) -> Vec<String> {
+        use pulldown_cmark::Event::*;
+        Parser::new_ext(s, Options::all())
+            .filter_map(|e| match e {
+                Text(t) | Code(t) => Some(t.to_string()),
+                SoftBreak => Some(" ".into()),
+                _ => None,
+            })
+            .collect::<String>()
+            .split(' ')
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn paragraphs_are_wrapped_at_the_line_width() {
+        let original = "aaa bbb ccc ddd eee fff ggg hhh\niii jjj";
+        let (s, _) = fmts_with_options(original, wrap(12));
+        assert_eq!(s, "aaa bbb ccc\nddd eee fff\nggg hhh iii\njjj");
+        assert_eq!(words(&s), words(original));
+    }
+
+    #[test]
+    fn padding_of_containers_is_counted() {
+        let original = "* aaa bbb ccc ddd\n\n  > eee fff ggg hhh";
+        let (s, _) = fmts_with_options(original, wrap(12));
+        assert_eq!(
+            s,
+            indoc!(
+                "
+                * aaa bbb
+                  ccc ddd
+                  
+                   > 
+                   > eee fff
+                   > ggg hhh"
+            )
+        );
+        assert_eq!(words(&s), words(original));
+    }
+
+    #[test]
+    fn inline_code_links_and_autolinks_are_never_broken() {
+        let original = "a `b c d e` f [g h](http://i.j/k \"l\") <http://m.n/o> p";
+        let (s, _) = fmts_with_options(original, wrap(6));
+        assert_eq!(s, "a\n`b c d e`\nf [g h](http://i.j/k \"l\")\n<http://m.n/o>\np");
+        assert_eq!(words(&s), words(original));
+    }
+
+    #[test]
+    fn lines_never_start_with_block_markers() {
+        for marker in ["#", "-", "+", "*", ">", "1.", "2)", "---", "==", "```"] {
+            let original = format!("aaa bbb \\{marker} ccc");
+            // The escaped marker is a text event of its own with a source range, which is escaped as it was.
+            let mut s = String::new();
+            cmark_with_options(Parser::new_ext(&original, Options::all()), &mut s, wrap(8)).unwrap();
+            let (s_with_source, _) = source_range::fmts_with_options(&original, wrap(8));
+            for s in [s, s_with_source] {
+                assert_eq!(words(&s), words(&original), "{}", s);
+                assert!(!s.lines().any(|l| l.starts_with(marker)), "{}", s);
+            }
+        }
+    }
+
+    #[test]
+    fn lines_never_start_with_inline_html() {
+        use pulldown_cmark::Event::*;
+        /// The events of `s` apart from text and line breaks, which wrapping changes.
+        fn structure(s: &str) -> Vec<pulldown_cmark::Event<'_>> {
+            Parser::new_ext(s, Options::all())
+                .filter(|e| !matches!(e, Text(_) | SoftBreak))
+                .collect()
+        }
+
+        for original in ["aaa <div> bbb", "aaa <!-- x --> bbb"] {
+            let (s, _) = fmts_with_options(original, wrap(3));
+            assert_eq!(structure(&s), structure(original), "{}", s);
+            assert_eq!(words(&s), words(original));
+        }
+    }
+
+    #[test]
+    fn unwrap_joins_lines() {
+        let options = CmarkToCmarkOptions {
+            wrap_mode: WrapMode::Unwrap,
+            ..Default::default()
+        };
+        let (s, _) = fmts_with_options("a\nb\n> c\n> d\n\n* e\n  f", options);
+        assert_eq!(s, "a b\n\n > \n > c d\n\n* e f");
+    }
+}
+
 mod frontmatter {
     use pulldown_cmark::{Options, Parser};
     use pulldown_cmark_to_cmark::{cmark, cmark_with_options};
@@ -1273,6 +2465,27 @@ key2: value2
         assert_eq!(input, output);
     }
 
+    #[test]
+    fn yaml_frontmatter_is_not_escaped() {
+        let input = "---
+title: *emphasis* [link]
+list:
+- a
+---
+
+# Frontmatter should be supported";
+
+        let events = Parser::new_ext(input, Options::ENABLE_YAML_STYLE_METADATA_BLOCKS);
+        let mut output = String::new();
+        let options = pulldown_cmark_to_cmark::Options {
+            escape_policy: pulldown_cmark_to_cmark::EscapePolicy::Paranoid,
+            ..Default::default()
+        };
+        cmark_with_options(events, &mut output, options).unwrap();
+
+        assert_eq!(input, output);
+    }
+
     #[test]
     fn toml_frontmatter_should_be_supported() {
         let input = "+++
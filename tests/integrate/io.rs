@@ -0,0 +1,68 @@
+use std::io;
+
+use pulldown_cmark::{Options, Parser};
+use pulldown_cmark_to_cmark::{cmark, cmark_io, cmark_io_with_source_range, cmark_resume_io, Error};
+
+const MARKDOWN: &str = "# Title\n\nSome *text* with a [link][l].\n\n* item\n\n[l]: https://example.com";
+
+/// A sink that accepts `capacity` bytes before failing.
+struct FailingWriter {
+    capacity: usize,
+}
+
+impl io::Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "sink is full"));
+        }
+        let written = buf.len().min(self.capacity);
+        self.capacity -= written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn it_writes_the_same_bytes_as_the_fmt_variant() {
+    let mut expected = String::new();
+    cmark(Parser::new_ext(MARKDOWN, Options::all()), &mut expected).unwrap();
+
+    let mut actual = Vec::new();
+    cmark_io(Parser::new_ext(MARKDOWN, Options::all()), &mut actual).unwrap();
+    assert_eq!(String::from_utf8(actual).unwrap(), expected);
+
+    let mut actual = Vec::new();
+    cmark_io_with_source_range(
+        Parser::new_ext(MARKDOWN, Options::all())
+            .into_offset_iter()
+            .map(|(e, r)| (e, Some(r))),
+        MARKDOWN,
+        &mut actual,
+    )
+    .unwrap();
+    assert_eq!(String::from_utf8(actual).unwrap(), MARKDOWN);
+}
+
+#[test]
+fn resumed_serialization_is_finalized_into_the_sink() {
+    let mut out = Vec::new();
+    let state = cmark_resume_io(Parser::new_ext(MARKDOWN, Options::all()), &mut out, None).unwrap();
+    assert_eq!(state.shortcuts.len(), 1);
+    let state = state.finalize_io(&mut out).unwrap();
+    assert!(state.shortcuts.is_empty());
+    assert!(String::from_utf8(out)
+        .unwrap()
+        .ends_with("\n\n[l]: https://example.com"));
+}
+
+#[test]
+fn sink_errors_are_reported_with_details() {
+    let err = cmark_io(Parser::new(MARKDOWN), FailingWriter { capacity: 5 }).unwrap_err();
+    match err {
+        Error::Io(err) => assert_eq!(err.kind(), io::ErrorKind::BrokenPipe),
+        err => panic!("expected an I/O error, got {:?}", err),
+    }
+}
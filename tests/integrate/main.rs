@@ -1,11 +1,13 @@
 mod display;
 mod fmt;
+mod io;
+mod serializer;
 mod spec;
 
 #[cfg(test)]
 mod fuzzed {
     use pulldown_cmark::{Event, HeadingLevel, Tag, TagEnd};
-    use pulldown_cmark_to_cmark::{cmark, Error};
+    use pulldown_cmark_to_cmark::{cmark, cmark_with_options, Error, EventValidation, Options, UnexpectedEvent};
 
     #[test]
     fn cmark_with_invalid_event_stream() {
@@ -26,10 +28,375 @@ mod fuzzed {
             Event::End(TagEnd::Heading(HeadingLevel::H2)),
             Event::End(TagEnd::Heading(HeadingLevel::H2)),
         ];
-        assert!(matches!(
-            cmark(events.iter(), String::new()),
-            Err(Error::UnexpectedEvent)
-        ));
+        let Err(Error::UnexpectedEvent(err)) = cmark(events.iter(), String::new()) else {
+            panic!("the nested heading is unexpected")
+        };
+        assert_eq!(
+            *err,
+            UnexpectedEvent {
+                index: 1,
+                event: events[1].clone(),
+                expected: Some(TagEnd::Heading(HeadingLevel::H2)),
+                open_containers: vec![TagEnd::Heading(HeadingLevel::H2)],
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Unexpected event 1 while reconstructing Markdown: Start(Heading(H2)), expected End(Heading(H2)) to \
+             close the open containers [Heading(H2)]"
+        );
+    }
+
+    #[test]
+    fn unexpected_text_is_displayed_as_a_string() {
+        let options = Options {
+            event_validation: EventValidation::Strict,
+            ..Default::default()
+        };
+        let Err(err) = cmark_with_options([Event::Text("a".into())].iter(), String::new(), options) else {
+            panic!("the text is outside of a block")
+        };
+        assert_eq!(
+            err.to_string(),
+            "Unexpected event 0 while reconstructing Markdown: Text(\"a\"), with no open containers"
+        );
+    }
+
+    #[test]
+    fn unmatched_end_events_are_located() {
+        let events = [
+            Event::Start(Tag::Paragraph),
+            Event::Text("a".into()),
+            Event::End(TagEnd::Link),
+            Event::End(TagEnd::Paragraph),
+        ];
+        let Err(err) = cmark(events.iter(), String::new()) else {
+            panic!("there is no link to end")
+        };
+        assert_eq!(
+            err.to_string(),
+            "Unexpected event 2 while reconstructing Markdown: End(Link), expected End(Paragraph) to close the \
+             open containers [Paragraph]"
+        );
+
+        let Err(err) = cmark([Event::End(TagEnd::Image)].iter(), String::new()) else {
+            panic!("there is no image to end")
+        };
+        assert_eq!(
+            err.to_string(),
+            "Unexpected event 0 while reconstructing Markdown: End(Image), with no open containers"
+        );
+    }
+}
+
+#[cfg(test)]
+mod validation {
+    use pulldown_cmark::{Alignment, Event, HeadingLevel, LinkType, Options as ParserOptions, Parser, Tag, TagEnd};
+    use pulldown_cmark_to_cmark::{
+        cmark_with_options, validate_events, Error, EventValidation, Options, Repair, RepairKind, Serializer,
+    };
+
+    fn repair(index: usize, kind: RepairKind) -> Repair {
+        Repair { index, kind }
+    }
+
+    fn link() -> Tag<'static> {
+        link_to("u")
+    }
+
+    fn link_to(dest_url: &'static str) -> Tag<'static> {
+        Tag::Link {
+            link_type: LinkType::Inline,
+            dest_url: dest_url.into(),
+            title: "".into(),
+            id: "".into(),
+        }
+    }
+
+    fn unexpected_index(res: Result<(), Error>) -> usize {
+        match res {
+            Err(Error::UnexpectedEvent(err)) => err.index,
+            res => panic!("expected an unexpected event, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parsed_events_are_valid() {
+        let markdown = "# Title\n\n- [x] *done* [link](u)\n\n  > quote\n\n| a |\n|---|\n| `b` |\n\n<div>\n\n```\ncode\n```\n\nfootnote[^1]\n\n[^1]: text\n";
+        validate_events(Parser::new_ext(markdown, ParserOptions::all())).unwrap();
+    }
+
+    #[test]
+    fn ends_must_close_the_innermost_open_container() {
+        let events = [
+            Event::Start(Tag::BlockQuote(None)),
+            Event::Start(Tag::Paragraph),
+            Event::Text("a".into()),
+            Event::End(TagEnd::BlockQuote(None)),
+        ];
+        assert_eq!(unexpected_index(validate_events(&events)), 3);
+        assert_eq!(unexpected_index(validate_events([Event::End(TagEnd::List(false))])), 0);
+    }
+
+    #[test]
+    fn events_must_appear_in_containers_that_can_hold_them() {
+        assert_eq!(unexpected_index(validate_events([Event::Text("stray".into())])), 0);
+        assert_eq!(
+            unexpected_index(validate_events([
+                Event::Start(Tag::Paragraph),
+                Event::Start(Tag::BlockQuote(None)),
+            ])),
+            1
+        );
+        assert_eq!(
+            unexpected_index(validate_events([
+                Event::Start(Tag::Table(vec![])),
+                Event::Start(Tag::TableCell)
+            ])),
+            1
+        );
+        assert_eq!(
+            unexpected_index(validate_events([
+                Event::Start(Tag::Paragraph),
+                Event::Start(link()),
+                Event::Start(Tag::Emphasis),
+                Event::Start(link()),
+            ])),
+            3
+        );
+    }
+
+    #[test]
+    fn containers_must_be_closed() {
+        let events = [
+            Event::Start(Tag::List(None)),
+            Event::Start(Tag::Item),
+            Event::Text("a".into()),
+            Event::End(TagEnd::Item),
+        ];
+        let Err(Error::UnclosedContainers(open)) = validate_events(&events) else {
+            panic!("the list is still open")
+        };
+        assert_eq!(open, [TagEnd::List(false)]);
+    }
+
+    #[test]
+    fn strict_validation_rejects_invalid_events_before_writing_anything() {
+        let events = [
+            Event::Start(Tag::Paragraph),
+            Event::Text("a".into()),
+            Event::End(TagEnd::Paragraph),
+            Event::Start(Tag::TableCell),
+            Event::Text("b".into()),
+            Event::End(TagEnd::TableCell),
+        ];
+        let mut unchecked = String::new();
+        cmark_with_options(events.iter(), &mut unchecked, Options::default()).unwrap();
+        assert_eq!(unchecked, "a\n\n|b");
+
+        let options = Options {
+            event_validation: EventValidation::Strict,
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let Err(Error::UnexpectedEvent(err)) = cmark_with_options(events.iter(), &mut output, options) else {
+            panic!("the table cell is outside of a table row")
+        };
+        assert_eq!(err.index, 3);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn strict_validation_reports_unclosed_containers_when_finishing() {
+        let options = Options {
+            event_validation: EventValidation::Strict,
+            ..Default::default()
+        };
+        let mut serializer = Serializer::new(String::new(), options);
+        serializer.push(Event::Start(Tag::BlockQuote(None))).unwrap();
+        serializer.push(Event::Start(Tag::Paragraph)).unwrap();
+        serializer.push(Event::Text("a".into())).unwrap();
+        let Err(Error::UnexpectedEvent(err)) = serializer.push(Event::End(TagEnd::BlockQuote(None))) else {
+            panic!("the paragraph is still open")
+        };
+        assert_eq!(err.expected, Some(TagEnd::Paragraph));
+
+        let Err(err) = serializer.finish() else {
+            panic!("the block quote and paragraph are still open")
+        };
+        assert_eq!(
+            err.to_string(),
+            "The events ended before closing the containers [BlockQuote(None), Paragraph]"
+        );
+    }
+
+    #[test]
+    fn repair_wraps_stray_inline_events_in_paragraphs_and_closes_open_containers() {
+        let options = Options {
+            event_validation: EventValidation::Repair,
+            ..Default::default()
+        };
+        let events = [
+            Event::Text("hello ".into()),
+            Event::Start(Tag::Emphasis),
+            Event::Text("world".into()),
+            Event::End(TagEnd::Emphasis),
+            Event::Start(Tag::Heading {
+                level: HeadingLevel::H1,
+                id: None,
+                classes: vec![],
+                attrs: vec![],
+            }),
+            Event::Text("heading".into()),
+        ];
+        let mut output = String::new();
+        let state = cmark_with_options(events.iter(), &mut output, options.clone()).unwrap();
+        assert_eq!(output, "hello *world*\n\n# heading");
+        assert_eq!(
+            state.repairs,
+            [
+                repair(0, RepairKind::Opened(TagEnd::Paragraph)),
+                repair(6, RepairKind::Closed(TagEnd::Heading(HeadingLevel::H1))),
+            ]
+        );
+
+        let events = [
+            Event::Start(Tag::BlockQuote(None)),
+            Event::Text("quote".into()),
+            Event::End(TagEnd::BlockQuote(None)),
+        ];
+        let mut output = String::new();
+        let state = cmark_with_options(events.iter(), &mut output, options).unwrap();
+        assert_eq!(output, "\n > \n > quote");
+        assert_eq!(state.repairs, [repair(1, RepairKind::Opened(TagEnd::Paragraph))]);
+    }
+
+    #[test]
+    fn repair_flattens_nested_links_and_drops_unmatched_ends() {
+        let events = [
+            Event::Start(Tag::Paragraph),
+            Event::Start(link_to("outer")),
+            Event::Text("a ".into()),
+            Event::Start(link_to("inner")),
+            Event::Text("b".into()),
+            Event::End(TagEnd::Link),
+            Event::End(TagEnd::Link),
+            Event::End(TagEnd::Paragraph),
+            Event::End(TagEnd::Paragraph),
+        ];
+        let options = Options {
+            event_validation: EventValidation::Repair,
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let state = cmark_with_options(events.iter(), &mut output, options).unwrap();
+        assert_eq!(output, "[a b](outer)");
+        assert_eq!(
+            state.repairs,
+            [
+                repair(3, RepairKind::Unwrapped(TagEnd::Link)),
+                repair(8, RepairKind::DroppedEnd(TagEnd::Paragraph)),
+            ]
+        );
+    }
+
+    #[test]
+    fn repair_closes_containers_left_open_within_an_ending_one() {
+        let events = [
+            Event::Start(Tag::List(None)),
+            Event::Start(Tag::Item),
+            Event::Start(Tag::Paragraph),
+            Event::Text("a".into()),
+            Event::End(TagEnd::List(false)),
+            Event::Rule,
+        ];
+        let options = Options {
+            event_validation: EventValidation::Repair,
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let state = cmark_with_options(events.iter(), &mut output, options).unwrap();
+        assert_eq!(output, "* a\n\n---");
+        assert_eq!(
+            state.repairs,
+            [
+                repair(4, RepairKind::Closed(TagEnd::Paragraph)),
+                repair(4, RepairKind::Closed(TagEnd::Item)),
+            ]
+        );
+    }
+
+    #[test]
+    fn repair_closes_containers_which_cannot_hold_a_block() {
+        let heading = |level| Tag::Heading {
+            level,
+            id: None,
+            classes: vec![],
+            attrs: vec![],
+        };
+        let events = [
+            Event::Start(heading(HeadingLevel::H1)),
+            Event::Text("a".into()),
+            Event::Start(heading(HeadingLevel::H2)),
+            Event::Text("b".into()),
+            Event::End(TagEnd::Heading(HeadingLevel::H2)),
+            Event::End(TagEnd::Heading(HeadingLevel::H1)),
+        ];
+        let options = Options {
+            event_validation: EventValidation::Repair,
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let state = cmark_with_options(events.iter(), &mut output, options).unwrap();
+        assert_eq!(output, "# a\n\n## b");
+        assert_eq!(
+            state.repairs,
+            [
+                repair(2, RepairKind::Closed(TagEnd::Heading(HeadingLevel::H1))),
+                repair(5, RepairKind::DroppedEnd(TagEnd::Heading(HeadingLevel::H1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn repair_wraps_table_cells_in_rows_and_unwraps_them_outside_of_tables() {
+        let options = Options {
+            event_validation: EventValidation::Repair,
+            ..Default::default()
+        };
+        let events = [
+            Event::Start(Tag::Table(vec![Alignment::None])),
+            Event::Start(Tag::TableHead),
+            Event::Start(Tag::TableCell),
+            Event::Text("a".into()),
+            Event::End(TagEnd::TableCell),
+            Event::End(TagEnd::TableHead),
+            Event::Start(Tag::TableCell),
+            Event::Text("b".into()),
+            Event::End(TagEnd::TableCell),
+            Event::End(TagEnd::Table),
+        ];
+        let mut output = String::new();
+        let state = cmark_with_options(events.iter(), &mut output, options.clone()).unwrap();
+        assert_eq!(output, "|a|\n|-|\n|b|");
+        assert_eq!(state.repairs, [repair(6, RepairKind::Opened(TagEnd::TableRow))]);
+
+        let events = [
+            Event::Start(Tag::TableCell),
+            Event::Text("a".into()),
+            Event::End(TagEnd::TableCell),
+            Event::Rule,
+        ];
+        let mut output = String::new();
+        let state = cmark_with_options(events.iter(), &mut output, options).unwrap();
+        assert_eq!(output, "a\n\n---");
+        assert_eq!(
+            state.repairs,
+            [
+                repair(0, RepairKind::Unwrapped(TagEnd::TableCell)),
+                repair(1, RepairKind::Opened(TagEnd::Paragraph)),
+            ]
+        );
     }
 }
 
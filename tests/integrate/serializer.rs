@@ -0,0 +1,79 @@
+use pulldown_cmark::{Event, LinkType, Options, Parser, Tag, TagEnd};
+use pulldown_cmark_to_cmark::{cmark, cmark_with_source_range, Options as CmarkToCmarkOptions, Serializer};
+
+const MARKDOWN: &str = "# Title\n\nSome *text* with a [link][l] and \\# escapes.\n\n* item\n\n[l]: https://example.com";
+
+fn fmts(s: &str) -> String {
+    let mut buf = String::new();
+    cmark(Parser::new_ext(s, Options::all()), &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn pushing_event_by_event_is_the_same_as_serializing_all_at_once() {
+    let mut serializer = Serializer::new(String::new(), CmarkToCmarkOptions::default());
+    for event in Parser::new_ext(MARKDOWN, Options::all()) {
+        serializer.push(event).unwrap();
+    }
+    let (buf, state) = serializer.finish().unwrap();
+    assert_eq!(buf, fmts(MARKDOWN));
+    assert!(state.shortcuts.is_empty(), "finishing writes all shortcuts");
+}
+
+#[test]
+fn suspended_serializers_can_be_resumed() {
+    let events: Vec<_> = Parser::new_ext(MARKDOWN, Options::all()).collect();
+    let (first, rest) = events.split_at(events.len() / 2);
+
+    let mut serializer = Serializer::new(String::new(), CmarkToCmarkOptions::default());
+    serializer.extend(first).unwrap();
+    let (buf, state) = serializer.suspend().unwrap();
+
+    let mut serializer = Serializer::resume(buf, state, CmarkToCmarkOptions::default());
+    serializer.extend(rest.iter().cloned()).unwrap();
+    assert_eq!(serializer.finish().unwrap().0, fmts(MARKDOWN));
+}
+
+#[test]
+fn lookahead_works_across_pushes() {
+    let link = Tag::Link {
+        link_type: LinkType::Inline,
+        dest_url: "u".into(),
+        title: "".into(),
+        id: "".into(),
+    };
+    let mut serializer = Serializer::new(String::new(), CmarkToCmarkOptions::default());
+    serializer
+        .extend([Event::Start(Tag::Paragraph), Event::Text("a!".into())])
+        .unwrap();
+    serializer.push(Event::Start(link)).unwrap();
+    serializer
+        .extend([
+            Event::Text("b".into()),
+            Event::End(TagEnd::Link),
+            Event::End(TagEnd::Paragraph),
+        ])
+        .unwrap();
+    assert_eq!(serializer.finish().unwrap().0, "a\\![b](u)");
+}
+
+#[test]
+fn ranges_preserve_escapes_of_the_source() {
+    let mut expected = String::new();
+    cmark_with_source_range(
+        Parser::new_ext(MARKDOWN, Options::all())
+            .into_offset_iter()
+            .map(|(e, r)| (e, Some(r))),
+        MARKDOWN,
+        &mut expected,
+    )
+    .unwrap();
+
+    let mut serializer = Serializer::new(String::new(), CmarkToCmarkOptions::default()).with_source(MARKDOWN);
+    for (event, range) in Parser::new_ext(MARKDOWN, Options::all()).into_offset_iter() {
+        serializer.push_with_range(event, range).unwrap();
+    }
+    let (buf, _) = serializer.finish().unwrap();
+    assert_eq!(buf, expected);
+    assert_eq!(buf, MARKDOWN);
+}
@@ -13,7 +13,7 @@ const COMMONMARK_SPEC_TEXT: &str = include_str!("../spec/CommonMark/spec.txt");
 const COMMONMARK_SPEC_EXAMPLE_COUNT: usize = 649;
 
 // At the time of writing, ~90% of tests pass. This needs some additional work.
-const EXPECTED_SUCCESS_EXAMPLE_COUNT: usize = 585;
+const EXPECTED_SUCCESS_EXAMPLE_COUNT: usize = 595;
 
 const FULL_CMARK_RESULTS_VAR: &str = "FULL_CMARK_RESULTS";
 
@@ -115,19 +115,19 @@ fn test_roundtrip(original: &str, expected_html: &str, line_number: usize, show_
                 .underline()
         );
 
-        eprintln!("{}\n", "Original Markdown Example");
+        eprintln!("Original Markdown Example\n");
         eprint_indented(original, "    ");
         eprintln!();
 
-        eprintln!("{}\n", "Regenerated Markdown Example");
+        eprintln!("Regenerated Markdown Example\n");
         eprint_indented(&regen_str, "    ");
         eprintln!();
 
-        eprintln!("{}\n", "Expected HTML");
+        eprintln!("Expected HTML\n");
         eprint_indented(expected_html, "    ");
         eprintln!();
 
-        eprintln!("{}\n", "Original vs Regenerated Event Sequence");
+        eprintln!("Original vs Regenerated Event Sequence\n");
         let comparision = PrettyComparison::new(&event_list, &event_list_2);
         for line in format!("{comparision}").lines() {
             eprintln!("    {}", line);